@@ -1,8 +1,14 @@
-use crate::setup::DebugUtils;
+use crate::{
+    mem::{LiveAllocations, StagingPool},
+    setup::DebugUtils,
+};
+use log::warn;
 use setup::DeviceQueues;
 use std::{mem::ManuallyDrop, sync::Arc};
+use tasks::{FencePool, TimelineReactor};
 
 pub mod mem;
+pub mod pipeline;
 pub mod setup;
 pub mod tasks;
 mod utils;
@@ -19,7 +25,7 @@ pub mod errors {
         #[error("{0}")]
         InstanceError(#[from] ash::InstanceError),
         #[error("{0}")]
-        VkError(#[from] ash::vk::Result),
+        VkError(ash::vk::Result),
         #[error("{0}")]
         VmaError(#[from] vk_mem::Error),
         #[error("No compute queue found")]
@@ -28,6 +34,37 @@ pub mod errors {
         NoTransferQueue,
         #[error("No physical device picked")]
         NoPhysicalDevicePicked,
+        #[error("The Vulkan app has been shut down")]
+        AppShutDown,
+        #[error("Invalid SPIR-V: {0}")]
+        InvalidSpirv(String),
+        #[error("Descriptor set doesn't match the shader: {0}")]
+        InvalidDescriptorSet(String),
+        #[error("Invalid image description: {0}")]
+        InvalidImageDesc(String),
+        #[error("Format {0:?} can't be transferred")]
+        UnsupportedFormat(ash::vk::Format),
+        #[error("The device has been lost")]
+        DeviceLost,
+        #[error("Timed out waiting for the GPU")]
+        Timeout,
+        #[error("The buffer is still used by commands that haven't completed")]
+        BufferInUse,
+        #[error("The other side of the stream has been dropped")]
+        StreamClosed,
+        #[error("Device extension {0} isn't enabled")]
+        MissingExtension(&'static str),
+        #[error("{0}")]
+        Io(#[from] std::io::Error),
+    }
+
+    impl From<ash::vk::Result> for VulkanError {
+        fn from(e: ash::vk::Result) -> Self {
+            match e {
+                ash::vk::Result::ERROR_DEVICE_LOST => Self::DeviceLost,
+                e => Self::VkError(e),
+            }
+        }
     }
 }
 
@@ -38,16 +75,52 @@ pub struct VulkanApp {
     pub(crate) device: ash::Device,
     pub(crate) vma: Arc<vk_mem::Allocator>,
     pub(crate) queues: DeviceQueues,
+    pub(crate) reactor: TimelineReactor,
+    pub(crate) staging: StagingPool,
+    /// Buffers and images still alive.
+    pub(crate) allocations: LiveAllocations,
+    pub(crate) fences: Arc<FencePool>,
+    /// Loaded when the device supports it, barriers fall back to the core API otherwise.
+    pub(crate) synchronization2: Option<ash::extensions::khr::Synchronization2>,
+    /// Loaded when the device can export fences as sync_fd.
+    #[cfg(all(target_os = "linux", feature = "sync-fd"))]
+    pub(crate) external_fence_fd: Option<ash::extensions::khr::ExternalFenceFd>,
+}
+
+impl VulkanApp {
+    /// Buffers and images allocated by the app that are still alive.
+    ///
+    /// Staging buffers are included until [`StagingPool::clear`] and while a submission uses them.
+    pub(crate) fn outstanding_allocations(&self) -> usize {
+        Arc::strong_count(&self.vma) - 1
+    }
 }
 
 impl Drop for VulkanApp {
     fn drop(&mut self) {
         unsafe {
-            // TODO: add destroys here
+            self.queues.close(&self.device, &self.reactor);
+            // Let every submission complete so the reactor can wake everyone before stopping
+            let _ = self.device.device_wait_idle();
+            self.reactor.shutdown();
+            // Also drops what the submissions were retaining
+            self.queues.destroy(&self.device);
+            self.staging.clear();
+            self.fences.destroy();
 
-            Arc::get_mut(&mut self.vma)
-                .expect("There still are buffers around referencing VMA !")
-                .destroy();
+            // Only buffers and images still own the allocator once the staging pool is cleared
+            let outstanding = self.outstanding_allocations();
+            match Arc::get_mut(&mut self.vma) {
+                Some(vma) => vma.destroy(),
+                None => {
+                    // Destroying the device would make dropping them unsound
+                    warn!(
+                        "{} buffers or images outlive the Vulkan app, leaking the device",
+                        outstanding
+                    );
+                    return;
+                }
+            }
 
             self.device.destroy_device(None);
             ManuallyDrop::drop(&mut self.debug_utils);
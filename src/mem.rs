@@ -1,6 +1,10 @@
-use crate::{errors::Result, VulkanApp};
+use crate::{
+    errors::Result,
+    tasks::{Owned, Transferred},
+    VulkanApp,
+};
 use ash::vk;
-use std::sync::Arc;
+use std::{ops::RangeBounds, sync::Arc};
 
 mod alloc;
 pub use alloc::*;
@@ -11,6 +15,140 @@ pub use buffer::*;
 mod gpu_buffer;
 pub use gpu_buffer::*;
 
+mod image;
+pub use image::*;
+
+mod mapped;
+pub use mapped::*;
+
+mod readback;
+pub use readback::*;
+
+mod slice;
+pub use slice::*;
+
+mod staging;
+pub(crate) use staging::*;
+
+mod upload;
+pub use upload::*;
+
+pub(crate) mod private {
+    use crate::tasks::{QueueOwner, Resource};
+    use ash::vk;
+    use parking_lot::{Mutex, MutexGuard};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    pub trait Sealed {
+        /// What a submission using this buffer must keep alive.
+        fn retained(&self) -> Resource;
+
+        /// Commands accessing this buffer that haven't completed yet.
+        fn in_flight(&self) -> &InFlight;
+
+        /// Size in bytes of the whole underlying Vulkan buffer, slices included.
+        fn whole_size(&self) -> vk::DeviceSize;
+
+        /// Queue family owning the buffer, `None` if it's shared between them.
+        fn owner(&self) -> Option<&QueueOwner>;
+
+        /// What a submission accessing this buffer must keep alive,
+        /// the buffer counts as in flight until it is dropped.
+        fn used(&self) -> Resource {
+            self.in_flight().track(self.retained())
+        }
+    }
+
+    /// Counts the commands accessing a buffer, from their recording until their submission
+    /// completes, or until they are dropped without being submitted.
+    #[derive(Clone, Default)]
+    pub struct InFlight(Arc<InFlightState>);
+
+    #[derive(Default)]
+    struct InFlightState {
+        count: AtomicUsize,
+        /// Held during host accesses through a shared handle, no command can start using the
+        /// buffer meanwhile.
+        host: Mutex<()>,
+    }
+
+    impl InFlight {
+        pub fn is_idle(&self) -> bool {
+            self.0.count.load(Ordering::Acquire) == 0
+        }
+
+        /// Keep new commands from using the buffer until the guard is dropped,
+        /// `None` if some already do.
+        pub fn host_access(&self) -> Option<MutexGuard<'_, ()>> {
+            let guard = self.0.host.lock();
+            self.is_idle().then_some(guard)
+        }
+
+        /// Count one more command until the returned resource is dropped, `retained` with it.
+        ///
+        /// Waits for a host access in progress to end.
+        pub fn track(&self, retained: Resource) -> Resource {
+            {
+                let _host = self.0.host.lock();
+                self.0.count.fetch_add(1, Ordering::AcqRel);
+            }
+            Arc::new(InFlightUse {
+                state: Arc::clone(&self.0),
+                _retained: retained,
+            })
+        }
+    }
+
+    struct InFlightUse {
+        state: Arc<InFlightState>,
+        _retained: Resource,
+    }
+
+    impl Drop for InFlightUse {
+        fn drop(&mut self) {
+            self.state.count.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Common interface of the buffer handles of this crate.
+///
+/// Sealed so that commands can only be recorded on buffers allocated by this crate.
+pub trait Buffer: private::Sealed {
+    type Item: Sized + Copy;
+
+    /// The underlying Vulkan handle.
+    fn handle(&self) -> vk::Buffer;
+
+    /// Size of the buffer in bytes.
+    fn size(&self) -> vk::DeviceSize;
+
+    /// Number of elements that fit in the buffer.
+    fn len(&self) -> usize {
+        (self.size() / std::mem::size_of::<Self::Item>() as vk::DeviceSize) as _
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Where the elements start in the underlying Vulkan buffer, in bytes.
+    fn byte_offset(&self) -> vk::DeviceSize {
+        0
+    }
+
+    /// Borrow a range of elements, e.g. `buffer.slice(16..32)` or `buffer.slice(..)`.
+    fn slice(&self, range: impl RangeBounds<usize>) -> BufferSlice<'_, Self::Item>
+    where
+        Self: Sized + Sync,
+    {
+        BufferSlice::new(self, range)
+    }
+}
+
 pub(crate) fn vma_ensure_mapped(
     vma: &vk_mem::Allocator,
     allocation: &vk_mem::Allocation,
@@ -23,61 +161,164 @@ pub(crate) fn vma_ensure_mapped(
     }
 }
 
+/// Queue families a resource is shared between, with `CONCURRENT` sharing when there are several.
+pub(crate) fn sharing_mode(families: &[u32]) -> vk::SharingMode {
+    if families.len() > 1 {
+        vk::SharingMode::CONCURRENT
+    } else {
+        vk::SharingMode::EXCLUSIVE
+    }
+}
+
+/// Ownership of a buffer to acquire before submitting commands using it, `None` if it's shared.
+pub(crate) fn owned_buffer<B: Buffer>(buffer: &B) -> Option<Owned> {
+    buffer.owner().map(|owner| Owned {
+        owner: owner.clone(),
+        resource: Transferred::Buffer(buffer.handle()),
+        retained: buffer.retained(),
+    })
+}
+
+/// Create a buffer usable by the queues of `families`, exclusive to one of them if there's only one.
 pub(crate) fn create_buffer(
     vma: Arc<vk_mem::Allocator>,
     size: vk::DeviceSize,
     usage: vk::BufferUsageFlags,
     location: vk_mem::MemoryUsage,
+    families: &[u32],
+) -> Result<(vk::Buffer, RawAllocation)> {
+    create_buffer_with_flags(
+        vma,
+        size,
+        usage,
+        location,
+        families,
+        vk_mem::AllocationCreateFlags::NONE,
+    )
+}
+
+/// Same as [`create_buffer`] but the memory stays mapped for the whole life of the buffer.
+pub(crate) fn create_mapped_buffer(
+    vma: Arc<vk_mem::Allocator>,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    location: vk_mem::MemoryUsage,
+    families: &[u32],
+) -> Result<(vk::Buffer, RawAllocation)> {
+    create_buffer_with_flags(
+        vma,
+        size,
+        usage,
+        location,
+        families,
+        vk_mem::AllocationCreateFlags::MAPPED,
+    )
+}
+
+fn create_buffer_with_flags(
+    vma: Arc<vk_mem::Allocator>,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    location: vk_mem::MemoryUsage,
+    families: &[u32],
+    flags: vk_mem::AllocationCreateFlags,
 ) -> Result<(vk::Buffer, RawAllocation)> {
     let (handle, allocation, info) = vma.create_buffer(
         &vk::BufferCreateInfo::builder()
             .size(size)
             .usage(usage)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            .sharing_mode(sharing_mode(families))
+            .queue_family_indices(families),
         &vk_mem::AllocationCreateInfo {
             usage: location,
+            flags,
             ..Default::default()
         },
     )?;
 
-    Ok((
-        handle,
-        RawAllocation {
-            allocation,
-            info,
-            size,
-            vma,
-        },
-    ))
+    match RawAllocation::new(Arc::clone(&vma), allocation, info, size) {
+        Ok(raw) => Ok((handle, raw)),
+        Err(e) => {
+            vma.destroy_buffer(handle, &allocation);
+            Err(e)
+        }
+    }
 }
 
 impl VulkanApp {
     /// Create a new CPU side buffer and immediately write some data in it.
+    ///
+    /// It can be used on every queue, see [`new_cpu_buffer_with_sharing`](Self::new_cpu_buffer_with_sharing).
     pub fn new_cpu_buffer<D: Sized + Copy>(
         &self,
         data: &[D],
         usage: vk::BufferUsageFlags,
+    ) -> Result<CpuToGpuBufferHandle<D>> {
+        self.new_cpu_buffer_with_sharing(data, usage, vk::SharingMode::CONCURRENT)
+    }
+
+    /// Same as [`new_cpu_buffer`](Self::new_cpu_buffer) but `EXCLUSIVE` sharing can be asked for,
+    /// which may be faster.
+    ///
+    /// Using an `EXCLUSIVE` buffer on a queue of another family than the last one to use it
+    /// first transfers its ownership, which costs two extra submissions.
+    pub fn new_cpu_buffer_with_sharing<D: Sized + Copy>(
+        &self,
+        data: &[D],
+        usage: vk::BufferUsageFlags,
+        sharing: vk::SharingMode,
     ) -> Result<CpuToGpuBufferHandle<D>> {
         let mut buffer = CpuToGpuBufferHandle::new(
             Arc::clone(&self.vma),
             (std::mem::size_of::<D>() * data.len()) as _,
             usage,
+            &self.sharing_families(sharing),
+            &self.allocations,
         )?;
         buffer.write_to(data)?;
         Ok(buffer)
     }
 
+    /// Create a buffer on the GPU and fill it through the transfer queue.
+    ///
+    /// It can be used on every queue, see [`upload_to_gpu_buffer_with_sharing`](Self::upload_to_gpu_buffer_with_sharing).
     pub async fn upload_to_gpu_buffer<D: Sized + Copy>(
         &self,
         data: &[D],
         usage: vk::BufferUsageFlags,
+    ) -> Result<GpuBufferHandle<D>> {
+        self.upload_to_gpu_buffer_with_sharing(data, usage, vk::SharingMode::CONCURRENT)
+            .await
+    }
+
+    /// Same as [`upload_to_gpu_buffer`](Self::upload_to_gpu_buffer) but `EXCLUSIVE` sharing can be
+    /// asked for.
+    ///
+    /// The buffer is always written and read back on the transfer queue, using an `EXCLUSIVE`
+    /// buffer on a queue of another family first transfers its ownership, and back again.
+    pub async fn upload_to_gpu_buffer_with_sharing<D: Sized + Copy>(
+        &self,
+        data: &[D],
+        usage: vk::BufferUsageFlags,
+        sharing: vk::SharingMode,
     ) -> Result<GpuBufferHandle<D>> {
         let mut buffer = GpuBufferHandle::new(
             Arc::clone(&self.vma),
             (std::mem::size_of::<D>() * data.len()) as _,
             usage,
+            &self.sharing_families(sharing),
+            &self.allocations,
         )?;
         buffer.write_to(self, data).await?;
         Ok(buffer)
     }
+
+    /// Queue families to share a new resource between.
+    pub(crate) fn sharing_families(&self, sharing: vk::SharingMode) -> Vec<u32> {
+        if sharing == vk::SharingMode::CONCURRENT {
+            self.queues.families()
+        } else {
+            Vec::new()
+        }
+    }
 }
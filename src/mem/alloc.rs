@@ -1,16 +1,161 @@
-use crate::{errors::Result, mem::vma_ensure_mapped};
-use ash::vk;
-use std::sync::Arc;
+use crate::{
+    errors::Result,
+    mem::{private::InFlight, sharing_mode, vma_ensure_mapped},
+    setup::{AllocationKind, LeakedAllocation},
+    tasks::QueueOwner,
+};
+use ash::vk::{self, Handle};
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+/// Buffers and images of the app that are still alive, to report those leaked at shutdown.
+///
+/// Staging buffers aren't registered, the app owns them.
+#[derive(Clone, Default)]
+pub(crate) struct LiveAllocations(Arc<Mutex<HashMap<(AllocationKind, u64), vk::DeviceSize>>>);
+
+impl LiveAllocations {
+    /// Registered until the returned registration is dropped.
+    pub(crate) fn register(
+        &self,
+        kind: AllocationKind,
+        handle: u64,
+        size: vk::DeviceSize,
+    ) -> LiveAllocation {
+        self.0.lock().insert((kind, handle), size);
+        LiveAllocation {
+            live: self.clone(),
+            key: (kind, handle),
+        }
+    }
+
+    /// Every allocation still alive, by kind and handle.
+    pub(crate) fn snapshot(&self) -> Vec<LeakedAllocation> {
+        let mut allocations: Vec<_> = self
+            .0
+            .lock()
+            .iter()
+            .map(|(&(kind, handle), &size)| LeakedAllocation { kind, handle, size })
+            .collect();
+        allocations.sort_by_key(|a| (a.kind, a.handle));
+        allocations
+    }
+}
+
+/// Keeps an allocation registered as alive.
+pub(crate) struct LiveAllocation {
+    live: LiveAllocations,
+    key: (AllocationKind, u64),
+}
+
+impl Drop for LiveAllocation {
+    fn drop(&mut self) {
+        self.live.0.lock().remove(&self.key);
+    }
+}
+
+/// A buffer and its memory, destroyed once the last handle or submission using it is gone.
+pub(crate) struct RawBuffer {
+    pub(crate) handle: vk::Buffer,
+    pub(crate) raw: RawAllocation,
+    pub(crate) in_flight: InFlight,
+    /// Only for `EXCLUSIVE` buffers.
+    pub(crate) owner: Option<QueueOwner>,
+    _live: LiveAllocation,
+}
+
+impl RawBuffer {
+    /// `families` it was created for, see [`sharing_mode`].
+    pub(crate) fn new(
+        handle: vk::Buffer,
+        raw: RawAllocation,
+        families: &[u32],
+        live: &LiveAllocations,
+    ) -> Self {
+        Self {
+            handle,
+            _live: live.register(AllocationKind::Buffer, handle.as_raw(), raw.size),
+            raw,
+            in_flight: InFlight::default(),
+            owner: (sharing_mode(families) == vk::SharingMode::EXCLUSIVE).then(QueueOwner::default),
+        }
+    }
+}
+
+impl Drop for RawBuffer {
+    fn drop(&mut self) {
+        self.raw
+            .vma
+            .destroy_buffer(self.handle, &self.raw.allocation);
+    }
+}
 
 pub(crate) struct RawAllocation {
     pub(crate) allocation: vk_mem::Allocation,
     pub(crate) info: vk_mem::AllocationInfo,
     pub(crate) size: vk::DeviceSize,
     pub(crate) vma: Arc<vk_mem::Allocator>,
+    /// `None` if the memory is host coherent, otherwise `nonCoherentAtomSize`.
+    non_coherent_atom: Option<vk::DeviceSize>,
 }
 
 impl RawAllocation {
-    pub fn write_to<D: Sized + Copy>(&mut self, data: &[D]) -> Result<()> {
+    pub(crate) fn new(
+        vma: Arc<vk_mem::Allocator>,
+        allocation: vk_mem::Allocation,
+        info: vk_mem::AllocationInfo,
+        size: vk::DeviceSize,
+    ) -> Result<Self> {
+        let memory_flags = vma.get_memory_type_properties(info.get_memory_type())?;
+        let non_coherent_atom = if memory_flags.contains(vk::MemoryPropertyFlags::HOST_COHERENT) {
+            None
+        } else {
+            Some(
+                vma.get_physical_device_properties()?
+                    .limits
+                    .non_coherent_atom_size,
+            )
+        };
+
+        Ok(Self {
+            allocation,
+            info,
+            size,
+            vma,
+            non_coherent_atom,
+        })
+    }
+
+    /// Expand a range to whole atoms, without going past the end of the allocation.
+    fn atom_range(
+        &self,
+        atom: vk::DeviceSize,
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+    ) -> (usize, usize) {
+        let start = offset / atom * atom;
+        let end = ((offset + size).div_ceil(atom) * atom).min(self.info.get_size() as _);
+        (start as _, (end - start) as _)
+    }
+
+    /// Make host writes in this range visible to the device, no-op on coherent memory.
+    pub(crate) fn flush(&self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        if let Some(atom) = self.non_coherent_atom {
+            let (offset, size) = self.atom_range(atom, offset, size);
+            self.vma.flush_allocation(&self.allocation, offset, size);
+        }
+    }
+
+    /// Make device writes in this range visible to the host, no-op on coherent memory.
+    pub(crate) fn invalidate(&self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        if let Some(atom) = self.non_coherent_atom {
+            let (offset, size) = self.atom_range(atom, offset, size);
+            self.vma
+                .invalidate_allocation(&self.allocation, offset, size);
+        }
+    }
+
+    pub fn write_to<D: Sized + Copy>(&self, data: &[D]) -> Result<()> {
         debug_assert!((std::mem::size_of::<D>() * data.len()) as vk::DeviceSize <= self.size);
 
         let (need_to_unmap, mapped_ptr) =
@@ -26,7 +171,7 @@ impl RawAllocation {
         };
 
         mapped_slice.copy_from_slice(data);
-        self.vma.flush_allocation(&self.allocation, 0, size as _);
+        self.flush(0, size);
 
         if need_to_unmap {
             self.vma.unmap_memory(&self.allocation);
@@ -36,14 +181,21 @@ impl RawAllocation {
     }
 
     pub fn read<D: Sized + Copy>(&self, out: &mut [D], offset: usize) -> Result<()> {
-        debug_assert!(((std::mem::size_of::<D>() * offset) as vk::DeviceSize) <= self.size);
-        debug_assert!(
-            ((std::mem::size_of::<D>() * (offset + out.len())) as vk::DeviceSize) <= self.size
+        let end = offset
+            .checked_add(out.len())
+            .and_then(|end| end.checked_mul(std::mem::size_of::<D>()));
+        assert!(
+            end.is_some_and(|end| end as vk::DeviceSize <= self.size),
+            "Read would overflow the buffer"
         );
 
         let (need_to_unmap, mapped_ptr) =
             vma_ensure_mapped(&self.vma, &self.allocation, &self.info)?;
 
+        self.invalidate(
+            (offset * std::mem::size_of::<D>()) as _,
+            std::mem::size_of_val(out) as _,
+        );
         let mapped_ptr = unsafe { mapped_ptr.offset((offset * std::mem::size_of::<D>()) as _) };
         let mapped_data = unsafe { std::slice::from_raw_parts(mapped_ptr as *const D, out.len()) };
 
@@ -1,20 +1,46 @@
-use crate::{errors::Result, mem, mem::RawAllocation};
+use crate::{
+    errors::{Result, VulkanError},
+    mem,
+    mem::{private, Buffer, LiveAllocations, MappedSlice, MappedSliceMut, RawBuffer},
+    tasks::{QueueOwner, Resource},
+};
 use ash::vk;
 use std::{marker::PhantomData, sync::Arc};
 
 /// Long-lived CPU buffer to store dynamic data later read from the GPU.
 /// Refer to [`vk_mem::MemoryUsage::CpuToGpu`]
 pub struct CpuToGpuBufferHandle<D> {
-    handle: vk::Buffer,
-    raw: RawAllocation,
+    inner: Arc<RawBuffer>,
     _marker: PhantomData<D>,
 }
 
-impl<D> Drop for CpuToGpuBufferHandle<D> {
-    fn drop(&mut self) {
-        self.raw
-            .vma
-            .destroy_buffer(self.handle, &self.raw.allocation);
+impl<D> private::Sealed for CpuToGpuBufferHandle<D> {
+    fn retained(&self) -> Resource {
+        Arc::clone(&self.inner) as _
+    }
+
+    fn in_flight(&self) -> &private::InFlight {
+        &self.inner.in_flight
+    }
+
+    fn whole_size(&self) -> vk::DeviceSize {
+        self.inner.raw.size
+    }
+
+    fn owner(&self) -> Option<&QueueOwner> {
+        self.inner.owner.as_ref()
+    }
+}
+
+impl<D: Sized + Copy> Buffer for CpuToGpuBufferHandle<D> {
+    type Item = D;
+
+    fn handle(&self) -> vk::Buffer {
+        self.inner.handle
+    }
+
+    fn size(&self) -> vk::DeviceSize {
+        self.inner.raw.size
     }
 }
 
@@ -23,21 +49,56 @@ impl<D: Sized + Copy> CpuToGpuBufferHandle<D> {
         vma: Arc<vk_mem::Allocator>,
         size: vk::DeviceSize,
         usage: vk::BufferUsageFlags,
+        families: &[u32],
+        live: &LiveAllocations,
     ) -> Result<Self> {
-        let (handle, raw) = mem::create_buffer(vma, size, usage, vk_mem::MemoryUsage::CpuToGpu)?;
+        // Stays mapped for the whole life of the buffer
+        let (handle, raw) =
+            mem::create_mapped_buffer(vma, size, usage, vk_mem::MemoryUsage::CpuToGpu, families)?;
 
         Ok(Self {
-            handle,
-            raw,
+            inner: Arc::new(RawBuffer::new(handle, raw, families, live)),
             _marker: Default::default(),
         })
     }
 
+    /// Host accesses fail with [`VulkanError::BufferInUse`] while recorded commands use the buffer,
+    /// until their submission completes.
+    ///
+    /// Mapping borrows the handle mutably, so no command can be recorded with it in the meantime.
+    fn ensure_idle(&self) -> Result<()> {
+        if self.inner.in_flight.is_idle() {
+            Ok(())
+        } else {
+            Err(VulkanError::BufferInUse)
+        }
+    }
+
     pub fn write_to(&mut self, data: &[D]) -> Result<()> {
-        self.raw.write_to(data)
+        self.ensure_idle()?;
+        self.inner.raw.write_to(data)
     }
 
+    /// Also fails while commands use the buffer, but only borrows the handle: commands recorded
+    /// meanwhile, e.g. through descriptor sets, wait for the read to end before using the buffer.
     pub fn read(&self, out: &mut [D], offset: usize) -> Result<()> {
-        self.raw.read(out, offset)
+        let _host = self
+            .inner
+            .in_flight
+            .host_access()
+            .ok_or(VulkanError::BufferInUse)?;
+        self.inner.raw.read(out, offset)
+    }
+
+    /// Look at the content of the buffer in place.
+    pub fn map(&mut self) -> Result<MappedSlice<'_, D>> {
+        self.ensure_idle()?;
+        Ok(MappedSlice::new(&self.inner.raw))
+    }
+
+    /// Write in place in the buffer, the writes are flushed when the guard is dropped.
+    pub fn map_mut(&mut self) -> Result<MappedSliceMut<'_, D>> {
+        self.ensure_idle()?;
+        Ok(MappedSliceMut::new(&self.inner.raw))
     }
 }
@@ -1,24 +1,57 @@
 use ash::vk;
-use std::{marker::PhantomData, sync::Arc};
+use std::{collections::VecDeque, marker::PhantomData, mem::MaybeUninit, ops::Range, sync::Arc};
 
 use crate::{
     errors::Result,
-    mem::{create_buffer, RawAllocation},
+    mem::{
+        create_buffer, owned_buffer, private, Buffer, LiveAllocations, RawBuffer, StagingLease,
+        STAGING_CHUNK_SIZE,
+    },
+    tasks::{QueueOwner, Resource},
+    utils::{as_bytes, as_uninit_bytes_mut, range_fits},
 };
 
 use crate::VulkanApp;
 
+/// Maximum amount of chunks of a single transfer submitted at the same time.
+const TRANSFER_MAX_CHUNKS_IN_FLIGHT: usize = 2;
+
+/// Buffer living on the GPU, written and read through staging buffers.
+///
+/// Dropping a transfer midway leaves the transferred range undefined, the staging buffers
+/// it was using are recycled in the background once the GPU is done with them.
 pub struct GpuBufferHandle<D> {
-    handle: vk::Buffer,
-    raw: RawAllocation,
+    inner: Arc<RawBuffer>,
     _marker: PhantomData<D>,
 }
 
-impl<D> Drop for GpuBufferHandle<D> {
-    fn drop(&mut self) {
-        self.raw
-            .vma
-            .destroy_buffer(self.handle, &self.raw.allocation);
+impl<D> private::Sealed for GpuBufferHandle<D> {
+    fn retained(&self) -> Resource {
+        Arc::clone(&self.inner) as _
+    }
+
+    fn in_flight(&self) -> &private::InFlight {
+        &self.inner.in_flight
+    }
+
+    fn whole_size(&self) -> vk::DeviceSize {
+        self.inner.raw.size
+    }
+
+    fn owner(&self) -> Option<&QueueOwner> {
+        self.inner.owner.as_ref()
+    }
+}
+
+impl<D: Sized + Copy> Buffer for GpuBufferHandle<D> {
+    type Item = D;
+
+    fn handle(&self) -> vk::Buffer {
+        self.inner.handle
+    }
+
+    fn size(&self) -> vk::DeviceSize {
+        self.inner.raw.size
     }
 }
 
@@ -27,6 +60,8 @@ impl<D: Sized + Copy> GpuBufferHandle<D> {
         vma: Arc<vk_mem::Allocator>,
         size: vk::DeviceSize,
         usage: vk::BufferUsageFlags,
+        families: &[u32],
+        live: &LiveAllocations,
     ) -> Result<Self> {
         let (handle, raw) = create_buffer(
             vma,
@@ -34,55 +69,136 @@ impl<D: Sized + Copy> GpuBufferHandle<D> {
             // To read and write with staging buffers
             usage | vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST,
             vk_mem::MemoryUsage::GpuOnly,
+            families,
         )?;
 
         Ok(Self {
-            handle,
-            raw,
+            inner: Arc::new(RawBuffer::new(handle, raw, families, live)),
             _marker: Default::default(),
         })
     }
 
     pub async fn write_to(&mut self, app: &VulkanApp, data: &[D]) -> Result<()> {
-        let (staging_handle, mut staging_raw) = create_buffer(
-            Arc::clone(&self.raw.vma),
-            self.raw.size,
-            vk::BufferUsageFlags::TRANSFER_SRC,
-            vk_mem::MemoryUsage::CpuOnly,
-        )?;
+        self.write_range(app, 0, data).await
+    }
+
+    /// Overwrite the elements starting at `offset`, only those are transferred.
+    pub async fn write_range(&mut self, app: &VulkanApp, offset: usize, data: &[D]) -> Result<()> {
+        assert!(
+            range_fits(offset, data.len(), self.len()),
+            "Write would overflow the buffer"
+        );
+        let offset = (offset * std::mem::size_of::<D>()) as vk::DeviceSize;
+        self.upload(app, offset, as_bytes(data)).await
+    }
 
-        staging_raw.write_to(data)?;
-        unsafe {
-            app.cmd_copy_buffer((staging_handle, &staging_raw), (self.handle, &self.raw))
-        }?
+    pub async fn read(&self, app: &VulkanApp, out: &mut [D], offset: usize) -> Result<()> {
+        assert!(
+            range_fits(offset, out.len(), self.len()),
+            "Read would overflow the buffer"
+        );
+        let offset = (offset * std::mem::size_of::<D>()) as vk::DeviceSize;
+        // Only initialized bytes are ever written through it
+        let out = unsafe { &mut *(out as *mut [D] as *mut [MaybeUninit<D>]) };
+        self.download(app, offset, as_uninit_bytes_mut(out)).await
+    }
+
+    /// Read back the given range of elements, only those are transferred.
+    pub async fn read_range(&self, app: &VulkanApp, range: Range<usize>) -> Result<Vec<D>> {
+        assert!(
+            range.start <= range.end && range.end <= self.len(),
+            "Range {:?} is out of the buffer",
+            range
+        );
+        let mut out = Vec::with_capacity(range.len());
+        let offset = (range.start * std::mem::size_of::<D>()) as vk::DeviceSize;
+        self.download(
+            app,
+            offset,
+            as_uninit_bytes_mut(&mut out.spare_capacity_mut()[..range.len()]),
+        )
         .await?;
+        unsafe { out.set_len(range.len()) };
+        Ok(out)
+    }
 
-        // Destroy staging
-        self.raw
-            .vma
-            .destroy_buffer(staging_handle, &staging_raw.allocation);
+    /// Copy bytes into the buffer through pooled staging buffers, chunk by chunk.
+    pub(crate) async fn upload(
+        &self,
+        app: &VulkanApp,
+        dst_offset: vk::DeviceSize,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let mut in_flight = VecDeque::with_capacity(TRANSFER_MAX_CHUNKS_IN_FLIGHT);
+
+        for (i, chunk) in bytes.chunks(STAGING_CHUNK_SIZE as _).enumerate() {
+            if in_flight.len() >= TRANSFER_MAX_CHUNKS_IN_FLIGHT {
+                in_flight.pop_front().unwrap().await?;
+            }
+
+            let mut staging = app.staging.acquire(&app.vma, chunk.len() as _)?;
+            staging.buffer_mut().raw.write_to(chunk)?;
+
+            let future = unsafe {
+                app.cmd_copy_buffer(
+                    (staging.buffer().handle, 0),
+                    (
+                        self.inner.handle,
+                        dst_offset + i as vk::DeviceSize * STAGING_CHUNK_SIZE,
+                    ),
+                    chunk.len() as _,
+                    Arc::clone(&self.inner) as _,
+                    owned_buffer(self),
+                )
+            }?;
+            staging.retire_after(&future);
+            in_flight.push_back(future);
+        }
+
+        for future in in_flight {
+            future.await?;
+        }
         Ok(())
     }
 
-    pub async fn read(&self, app: &VulkanApp, out: &mut [D], offset: usize) -> Result<()> {
-        let (staging_handle, staging_raw) = create_buffer(
-            Arc::clone(&self.raw.vma),
-            self.raw.size,
-            vk::BufferUsageFlags::TRANSFER_DST,
-            vk_mem::MemoryUsage::CpuOnly,
-        )?;
+    /// Copy bytes out of the buffer through pooled staging buffers, chunk by chunk.
+    pub(crate) async fn download(
+        &self,
+        app: &VulkanApp,
+        src_offset: vk::DeviceSize,
+        out: &mut [MaybeUninit<u8>],
+    ) -> Result<()> {
+        let mut in_flight: VecDeque<(StagingLease, _, _)> =
+            VecDeque::with_capacity(TRANSFER_MAX_CHUNKS_IN_FLIGHT);
 
-        unsafe {
-            app.cmd_copy_buffer((self.handle, &self.raw), (staging_handle, &staging_raw))
-        }?
-        .await?;
+        for (i, chunk) in out.chunks_mut(STAGING_CHUNK_SIZE as _).enumerate() {
+            if in_flight.len() >= TRANSFER_MAX_CHUNKS_IN_FLIGHT {
+                let (staging, future, chunk) = in_flight.pop_front().unwrap();
+                future.await?;
+                staging.buffer().raw.read(chunk, 0)?;
+            }
 
-        staging_raw.read(out, offset)?;
+            let mut staging = app.staging.acquire(&app.vma, chunk.len() as _)?;
+            let future = unsafe {
+                app.cmd_copy_buffer(
+                    (
+                        self.inner.handle,
+                        src_offset + i as vk::DeviceSize * STAGING_CHUNK_SIZE,
+                    ),
+                    (staging.buffer().handle, 0),
+                    chunk.len() as _,
+                    Arc::clone(&self.inner) as _,
+                    owned_buffer(self),
+                )
+            }?;
+            staging.retire_after(&future);
+            in_flight.push_back((staging, future, chunk));
+        }
 
-        // Destroy staging
-        self.raw
-            .vma
-            .destroy_buffer(staging_handle, &staging_raw.allocation);
+        for (staging, future, chunk) in in_flight {
+            future.await?;
+            staging.buffer().raw.read(chunk, 0)?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,558 @@
+use ash::vk::{self, Handle};
+use parking_lot::Mutex;
+use std::{ops::Range, slice::from_ref, sync::Arc};
+
+use crate::{
+    errors::{Result, VulkanError},
+    mem::{sharing_mode, LiveAllocation, LiveAllocations, RawAllocation},
+    setup::{AllocationKind, QueueKind},
+    tasks::{Owned, QueueOwner, Resource, Transferred},
+    VulkanApp,
+};
+
+/// Shape, format and usage of a [`GpuImageHandle`].
+#[derive(Debug, Copy, Clone)]
+pub struct ImageDesc {
+    pub image_type: vk::ImageType,
+    pub extent: vk::Extent3D,
+    pub format: vk::Format,
+    pub mip_levels: u32,
+    pub array_layers: u32,
+    pub usage: vk::ImageUsageFlags,
+    /// `CONCURRENT` by default. Using an `EXCLUSIVE` image on a queue of another family than the
+    /// last one to use it first transfers its ownership, its transfers are on the graphics queue
+    /// for depth and stencil formats and on the transfer queue otherwise.
+    pub sharing: vk::SharingMode,
+}
+
+impl ImageDesc {
+    pub fn d2(width: u32, height: u32, format: vk::Format) -> Self {
+        Self {
+            image_type: vk::ImageType::TYPE_2D,
+            extent: vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            },
+            format,
+            mip_levels: 1,
+            array_layers: 1,
+            usage: vk::ImageUsageFlags::empty(),
+            sharing: vk::SharingMode::CONCURRENT,
+        }
+    }
+
+    pub fn d3(width: u32, height: u32, depth: u32, format: vk::Format) -> Self {
+        Self {
+            image_type: vk::ImageType::TYPE_3D,
+            extent: vk::Extent3D {
+                width,
+                height,
+                depth,
+            },
+            ..Self::d2(width, height, format)
+        }
+    }
+
+    pub fn mip_levels(mut self, mip_levels: u32) -> Self {
+        self.mip_levels = mip_levels;
+        self
+    }
+
+    pub fn array_layers(mut self, array_layers: u32) -> Self {
+        self.array_layers = array_layers;
+        self
+    }
+
+    pub fn usage(mut self, usage: vk::ImageUsageFlags) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    pub fn sharing(mut self, sharing: vk::SharingMode) -> Self {
+        self.sharing = sharing;
+        self
+    }
+
+    /// Extent of a mip level, never smaller than 1 texel.
+    pub fn level_extent(&self, level: u32) -> vk::Extent3D {
+        vk::Extent3D {
+            width: (self.extent.width >> level).max(1),
+            height: (self.extent.height >> level).max(1),
+            depth: (self.extent.depth >> level).max(1),
+        }
+    }
+
+    /// Size in bytes of a mip level, all array layers included.
+    ///
+    /// Fails with [`VulkanError::UnsupportedFormat`] for formats that can't be transferred,
+    /// see [`format_texel_size`].
+    pub fn level_size(&self, level: u32) -> Result<vk::DeviceSize> {
+        let extent = self.level_extent(level);
+        let texel =
+            format_texel_size(self.format).ok_or(VulkanError::UnsupportedFormat(self.format))?;
+
+        Ok(extent.width as vk::DeviceSize
+            * extent.height as vk::DeviceSize
+            * extent.depth as vk::DeviceSize
+            * self.array_layers as vk::DeviceSize
+            * texel as vk::DeviceSize)
+    }
+
+    /// Check the combination of parameters, the rest is left to the validation layers.
+    fn validate(&self) -> Result<()> {
+        let invalid = |msg: String| Err(VulkanError::InvalidImageDesc(msg));
+
+        if self.image_type == vk::ImageType::TYPE_3D && self.array_layers > 1 {
+            return invalid(format!(
+                "3D images can't have {} array layers",
+                self.array_layers
+            ));
+        }
+        if self.mip_levels == 0 || self.array_layers == 0 {
+            return invalid("an image needs at least one mip level and array layer".into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn aspect(&self) -> vk::ImageAspectFlags {
+        use vk::Format as F;
+
+        match self.format {
+            F::D16_UNORM | F::X8_D24_UNORM_PACK32 | F::D32_SFLOAT => vk::ImageAspectFlags::DEPTH,
+            F::S8_UINT => vk::ImageAspectFlags::STENCIL,
+            F::D16_UNORM_S8_UINT | F::D24_UNORM_S8_UINT | F::D32_SFLOAT_S8_UINT => {
+                vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+            }
+            _ => vk::ImageAspectFlags::COLOR,
+        }
+    }
+
+    /// Depth and stencil copies are only allowed on queues supporting graphics.
+    fn transfer_queue(&self) -> QueueKind {
+        if self.aspect() == vk::ImageAspectFlags::COLOR {
+            QueueKind::Transfer
+        } else {
+            QueueKind::Graphics
+        }
+    }
+}
+
+/// Size in bytes of one texel of an uncompressed format.
+pub fn format_texel_size(format: vk::Format) -> Option<u32> {
+    use vk::Format as F;
+
+    Some(match format {
+        F::R8_UNORM | F::R8_SNORM | F::R8_UINT | F::R8_SINT | F::R8_SRGB | F::S8_UINT => 1,
+        F::R8G8_UNORM
+        | F::R8G8_SNORM
+        | F::R8G8_UINT
+        | F::R8G8_SINT
+        | F::R8G8_SRGB
+        | F::R16_UNORM
+        | F::R16_SNORM
+        | F::R16_UINT
+        | F::R16_SINT
+        | F::R16_SFLOAT
+        | F::D16_UNORM => 2,
+        F::R8G8B8A8_UNORM
+        | F::R8G8B8A8_SNORM
+        | F::R8G8B8A8_UINT
+        | F::R8G8B8A8_SINT
+        | F::R8G8B8A8_SRGB
+        | F::B8G8R8A8_UNORM
+        | F::B8G8R8A8_SRGB
+        | F::A2B10G10R10_UNORM_PACK32
+        | F::B10G11R11_UFLOAT_PACK32
+        | F::R16G16_UNORM
+        | F::R16G16_SNORM
+        | F::R16G16_UINT
+        | F::R16G16_SINT
+        | F::R16G16_SFLOAT
+        | F::R32_UINT
+        | F::R32_SINT
+        | F::R32_SFLOAT
+        | F::X8_D24_UNORM_PACK32
+        | F::D32_SFLOAT => 4,
+        F::R16G16B16A16_UNORM
+        | F::R16G16B16A16_SNORM
+        | F::R16G16B16A16_UINT
+        | F::R16G16B16A16_SINT
+        | F::R16G16B16A16_SFLOAT
+        | F::R32G32_UINT
+        | F::R32G32_SINT
+        | F::R32G32_SFLOAT => 8,
+        F::R32G32B32_UINT | F::R32G32B32_SINT | F::R32G32B32_SFLOAT => 12,
+        F::R32G32B32A32_UINT | F::R32G32B32A32_SINT | F::R32G32B32A32_SFLOAT => 16,
+        _ => return None,
+    })
+}
+
+/// Layout of each mip level of an image, as of the last submission transitioning it.
+pub(crate) type ImageLayouts = Arc<Mutex<Vec<vk::ImageLayout>>>;
+
+/// Image living on the GPU, written and read through staging buffers.
+///
+/// Between operations each written mip level is kept in the `GENERAL` layout.
+pub struct GpuImageHandle {
+    inner: Arc<RawImage>,
+    desc: ImageDesc,
+    /// Current layout of each mip level.
+    layouts: ImageLayouts,
+    /// Only for `EXCLUSIVE` images.
+    owner: Option<QueueOwner>,
+}
+
+/// An image and its memory, destroyed once the last handle or submission using it is gone.
+struct RawImage {
+    handle: vk::Image,
+    raw: RawAllocation,
+    _live: LiveAllocation,
+}
+
+impl Drop for RawImage {
+    fn drop(&mut self) {
+        self.raw
+            .vma
+            .destroy_image(self.handle, &self.raw.allocation);
+    }
+}
+
+impl GpuImageHandle {
+    pub(crate) fn new(
+        vma: Arc<vk_mem::Allocator>,
+        desc: ImageDesc,
+        families: &[u32],
+        live: &LiveAllocations,
+    ) -> Result<Self> {
+        let (handle, allocation, info) = vma.create_image(
+            &vk::ImageCreateInfo::builder()
+                .image_type(desc.image_type)
+                .extent(desc.extent)
+                .format(desc.format)
+                .mip_levels(desc.mip_levels)
+                .array_layers(desc.array_layers)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                // To read and write with staging buffers
+                .usage(
+                    desc.usage
+                        | vk::ImageUsageFlags::TRANSFER_SRC
+                        | vk::ImageUsageFlags::TRANSFER_DST,
+                )
+                .sharing_mode(sharing_mode(families))
+                .queue_family_indices(families)
+                .initial_layout(vk::ImageLayout::UNDEFINED),
+            &vk_mem::AllocationCreateInfo {
+                usage: vk_mem::MemoryUsage::GpuOnly,
+                ..Default::default()
+            },
+        )?;
+
+        let size = info.get_size() as _;
+        let raw = match RawAllocation::new(Arc::clone(&vma), allocation, info, size) {
+            Ok(raw) => raw,
+            Err(e) => {
+                vma.destroy_image(handle, &allocation);
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            inner: Arc::new(RawImage {
+                handle,
+                _live: live.register(AllocationKind::Image, handle.as_raw(), raw.size),
+                raw,
+            }),
+            desc,
+            layouts: Arc::new(Mutex::new(vec![
+                vk::ImageLayout::UNDEFINED;
+                desc.mip_levels as _
+            ])),
+            owner: (sharing_mode(families) == vk::SharingMode::EXCLUSIVE).then(QueueOwner::default),
+        })
+    }
+
+    pub fn handle(&self) -> vk::Image {
+        self.inner.handle
+    }
+
+    pub fn desc(&self) -> &ImageDesc {
+        &self.desc
+    }
+
+    /// Write the first mip level, every array layer.
+    pub async fn write_to<D: Sized + Copy>(&mut self, app: &VulkanApp, data: &[D]) -> Result<()> {
+        self.write_level(app, 0, data).await
+    }
+
+    /// Write a whole mip level, every array layer, layers are expected one after the other.
+    pub async fn write_level<D: Sized + Copy>(
+        &mut self,
+        app: &VulkanApp,
+        level: u32,
+        data: &[D],
+    ) -> Result<()> {
+        assert!(level < self.desc.mip_levels, "No mip level {}", level);
+        let size = self.desc.level_size(level)?;
+        assert_eq!(
+            std::mem::size_of_val(data) as vk::DeviceSize,
+            size,
+            "Data doesn't match the size of the mip level"
+        );
+
+        let mut staging = app.staging.acquire(&app.vma, size)?;
+        staging.buffer_mut().raw.write_to(data)?;
+        let staging_handle = staging.buffer().handle;
+
+        // Reads the layouts, so before locking them
+        if let Some(owned) = self.owned() {
+            app.acquire_ownership(&owned, self.desc.transfer_queue())?;
+        }
+        let future = {
+            let mut layouts = self.layouts.lock();
+            let mut commands = unsafe {
+                app.record_commands(self.desc.transfer_queue(), |device, cmd| {
+                    self.cmd_transition(
+                        device,
+                        cmd,
+                        level,
+                        layouts[level as usize],
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        vk::AccessFlags::TRANSFER_WRITE,
+                    );
+                    device.cmd_copy_buffer_to_image(
+                        cmd,
+                        staging_handle,
+                        self.inner.handle,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        from_ref(&self.level_copy(level)),
+                    );
+                    self.cmd_transition(
+                        device,
+                        cmd,
+                        level,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        vk::ImageLayout::GENERAL,
+                        vk::AccessFlags::TRANSFER_WRITE,
+                    );
+                    Ok(())
+                })?
+            };
+            commands.retain(Arc::clone(&self.inner) as _);
+            let future = commands.submit()?;
+            layouts[level as usize] = vk::ImageLayout::GENERAL;
+            future
+        };
+
+        staging.retire_after(&future);
+        future.await
+    }
+
+    /// Read the first mip level, every array layer.
+    pub async fn read<D: Sized + Copy>(&self, app: &VulkanApp, out: &mut [D]) -> Result<()> {
+        self.read_level(app, 0, out).await
+    }
+
+    /// Read a whole mip level, every array layer, layers are written one after the other.
+    pub async fn read_level<D: Sized + Copy>(
+        &self,
+        app: &VulkanApp,
+        level: u32,
+        out: &mut [D],
+    ) -> Result<()> {
+        assert!(level < self.desc.mip_levels, "No mip level {}", level);
+        let size = self.desc.level_size(level)?;
+        assert_eq!(
+            std::mem::size_of_val(out) as vk::DeviceSize,
+            size,
+            "Output doesn't match the size of the mip level"
+        );
+
+        let mut staging = app.staging.acquire(&app.vma, size)?;
+        let staging_handle = staging.buffer().handle;
+
+        // Reads the layouts, so before locking them
+        if let Some(owned) = self.owned() {
+            app.acquire_ownership(&owned, self.desc.transfer_queue())?;
+        }
+        let future = {
+            let mut layouts = self.layouts.lock();
+            let mut commands = unsafe {
+                app.record_commands(self.desc.transfer_queue(), |device, cmd| {
+                    self.cmd_transition(
+                        device,
+                        cmd,
+                        level,
+                        layouts[level as usize],
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        vk::AccessFlags::TRANSFER_READ,
+                    );
+                    device.cmd_copy_image_to_buffer(
+                        cmd,
+                        self.inner.handle,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        staging_handle,
+                        from_ref(&self.level_copy(level)),
+                    );
+                    self.cmd_transition(
+                        device,
+                        cmd,
+                        level,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        vk::ImageLayout::GENERAL,
+                        vk::AccessFlags::TRANSFER_READ,
+                    );
+                    Ok(())
+                })?
+            };
+            commands.retain(Arc::clone(&self.inner) as _);
+            let future = commands.submit()?;
+            layouts[level as usize] = vk::ImageLayout::GENERAL;
+            future
+        };
+
+        staging.retire_after(&future);
+        future.await?;
+        staging.buffer().raw.read(out, 0)
+    }
+
+    /// What a submission using this image must keep alive.
+    pub(crate) fn retained(&self) -> Resource {
+        Arc::clone(&self.inner) as _
+    }
+
+    /// Layout of each mip level, only updated once a submission transitioning them succeeds.
+    pub(crate) fn layouts(&self) -> &ImageLayouts {
+        &self.layouts
+    }
+
+    /// Ownership to acquire before submitting commands using the image, `None` if it's shared.
+    pub(crate) fn owned(&self) -> Option<Owned> {
+        self.owner.as_ref().map(|owner| Owned {
+            owner: owner.clone(),
+            resource: Transferred::Image {
+                handle: self.inner.handle,
+                aspect: self.desc.aspect(),
+                layers: self.desc.array_layers,
+                layouts: Arc::clone(&self.layouts),
+            },
+            retained: self.retained(),
+        })
+    }
+
+    /// Panics if `levels` are out of the image.
+    pub(crate) fn check_levels(&self, levels: &Range<u32>) {
+        assert!(
+            levels.start <= levels.end && levels.end <= self.desc.mip_levels,
+            "Mip levels {:?} are out of the image",
+            levels
+        );
+    }
+
+    /// Every array layer of one mip level.
+    pub(crate) fn level_range(&self, level: u32) -> vk::ImageSubresourceRange {
+        vk::ImageSubresourceRange::builder()
+            .aspect_mask(self.desc.aspect())
+            .base_mip_level(level)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(self.desc.array_layers)
+            .build()
+    }
+
+    pub(crate) fn level_copy(&self, level: u32) -> vk::BufferImageCopy {
+        vk::BufferImageCopy::builder()
+            .buffer_offset(0)
+            .buffer_row_length(0)
+            .buffer_image_height(0)
+            .image_subresource(
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(self.desc.aspect())
+                    .mip_level(level)
+                    .base_array_layer(0)
+                    .layer_count(self.desc.array_layers)
+                    .build(),
+            )
+            .image_offset(vk::Offset3D::default())
+            .image_extent(self.desc.level_extent(level))
+            .build()
+    }
+
+    /// Layout transition of one mip level around a transfer.
+    ///
+    /// Transitions to `GENERAL` make the transfer available to everything that comes after.
+    unsafe fn cmd_transition(
+        &self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        level: u32,
+        old: vk::ImageLayout,
+        new: vk::ImageLayout,
+        transfer_access: vk::AccessFlags,
+    ) {
+        let (src_stage, src_access, dst_stage, dst_access) = if new == vk::ImageLayout::GENERAL {
+            (
+                vk::PipelineStageFlags::TRANSFER,
+                transfer_access,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE,
+            )
+        } else {
+            (
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::AccessFlags::MEMORY_WRITE,
+                vk::PipelineStageFlags::TRANSFER,
+                transfer_access,
+            )
+        };
+
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .old_layout(old)
+            .new_layout(new)
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(self.inner.handle)
+            .subresource_range(self.level_range(level));
+
+        device.cmd_pipeline_barrier(
+            cmd,
+            src_stage,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            from_ref(&barrier),
+        );
+    }
+}
+
+impl VulkanApp {
+    /// Create an image on the GPU, its content is undefined until written.
+    pub fn new_gpu_image(&self, desc: ImageDesc) -> Result<GpuImageHandle> {
+        desc.validate()?;
+        GpuImageHandle::new(
+            Arc::clone(&self.vma),
+            desc,
+            &self.sharing_families(desc.sharing),
+            &self.allocations,
+        )
+    }
+
+    /// Create an image on the GPU and fill its first mip level.
+    ///
+    /// Fails with [`VulkanError::UnsupportedFormat`] before creating anything if the format
+    /// can't be transferred.
+    pub async fn upload_to_gpu_image<D: Sized + Copy>(
+        &self,
+        desc: ImageDesc,
+        data: &[D],
+    ) -> Result<GpuImageHandle> {
+        desc.level_size(0)?;
+        let mut image = self.new_gpu_image(desc)?;
+        image.write_to(self, data).await?;
+        Ok(image)
+    }
+}
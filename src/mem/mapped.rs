@@ -0,0 +1,87 @@
+use crate::mem::RawAllocation;
+use std::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+/// Read access to the memory of a persistently mapped buffer.
+///
+/// The memory is invalidated when the guard is created so that writes from the GPU are visible.
+pub struct MappedSlice<'a, D> {
+    data: &'a [D],
+}
+
+impl<'a, D: Sized + Copy> MappedSlice<'a, D> {
+    pub(crate) fn new(raw: &'a RawAllocation) -> Self {
+        raw.invalidate(0, raw.size);
+        Self {
+            data: unsafe { std::slice::from_raw_parts(mapped_ptr(raw), element_count::<D>(raw)) },
+        }
+    }
+}
+
+impl<D> Deref for MappedSlice<'_, D> {
+    type Target = [D];
+
+    fn deref(&self) -> &[D] {
+        self.data
+    }
+}
+
+/// Write access to the memory of a persistently mapped buffer.
+///
+/// The memory is flushed when the guard is dropped so that writes are visible to the GPU.
+pub struct MappedSliceMut<'a, D> {
+    raw: &'a RawAllocation,
+    data: *mut D,
+    len: usize,
+    _marker: PhantomData<&'a mut [D]>,
+}
+
+impl<'a, D: Sized + Copy> MappedSliceMut<'a, D> {
+    /// The allocation is only borrowed immutably to be flushed, the owner must be borrowed mutably.
+    pub(crate) fn new(raw: &'a RawAllocation) -> Self {
+        raw.invalidate(0, raw.size);
+        Self {
+            raw,
+            data: mapped_ptr(raw),
+            len: element_count::<D>(raw),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<D> Deref for MappedSliceMut<'_, D> {
+    type Target = [D];
+
+    fn deref(&self) -> &[D] {
+        unsafe { std::slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl<D> DerefMut for MappedSliceMut<'_, D> {
+    fn deref_mut(&mut self) -> &mut [D] {
+        unsafe { std::slice::from_raw_parts_mut(self.data, self.len) }
+    }
+}
+
+impl<D> Drop for MappedSliceMut<'_, D> {
+    fn drop(&mut self) {
+        self.raw.flush(0, self.raw.size);
+    }
+}
+
+fn mapped_ptr<D>(raw: &RawAllocation) -> *mut D {
+    let ptr = raw.info.get_mapped_data();
+    assert!(!ptr.is_null(), "Buffer isn't persistently mapped");
+    assert_eq!(
+        ptr as usize % std::mem::align_of::<D>(),
+        0,
+        "Mapped memory isn't aligned for the element type"
+    );
+    ptr as *mut D
+}
+
+fn element_count<D>(raw: &RawAllocation) -> usize {
+    raw.size as usize / std::mem::size_of::<D>()
+}
@@ -0,0 +1,272 @@
+use crate::{
+    errors::{Result, VulkanError},
+    mem::{owned_buffer, Buffer, StagingLease},
+    setup::QueueKind,
+    tasks::TimelineFuture,
+    utils::as_uninit_bytes_mut,
+    VulkanApp,
+};
+use ash::vk;
+use futures::{future::poll_fn, Stream};
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    slice::from_ref,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+/// Reads back buffers continuously, their content is yielded by the [`ReadbackStream`] created
+/// with it, in the order they were pushed.
+///
+/// At most `depth` readbacks are in flight or waiting to be consumed, which bounds the staging
+/// memory used: pushing waits for the stream to consume the oldest one.
+/// The stream ends once this side is closed or dropped and everything has been consumed.
+///
+/// ```ignore
+/// // One output buffer per readback in flight
+/// let (mut readbacks, results) = ReadbackQueue::new(&app, outputs.len());
+/// let produce = async {
+///     for i in 0..frames {
+///         // The output buffer of this slot has been read back once there is room
+///         readbacks.ready().await?;
+///         let slot = i % outputs.len();
+///         let computed = app
+///             .record(QueueKind::Compute, |rec| rec.dispatch(&pipeline, &[&sets[slot]], x, 1, 1))?
+///             .submit()?;
+///         readbacks.push(&outputs[slot], &[&computed]).await?;
+///     }
+///     readbacks.close();
+///     Ok(())
+/// };
+/// let consume = results.try_for_each(|values: Vec<f32>| async move { Ok(()) });
+/// futures::try_join!(produce, consume)?;
+/// ```
+pub struct ReadbackQueue<'a, D> {
+    app: &'a VulkanApp,
+    depth: usize,
+    shared: Arc<Mutex<Shared<'a>>>,
+    _marker: PhantomData<D>,
+}
+
+/// Content of the buffers pushed to a [`ReadbackQueue`], as their copies complete.
+pub struct ReadbackStream<'a, D> {
+    shared: Arc<Mutex<Shared<'a>>>,
+    _marker: PhantomData<D>,
+}
+
+#[derive(Default)]
+struct Shared<'a> {
+    readbacks: VecDeque<Readback<'a>>,
+    /// The queue has been closed or dropped, nothing else will be pushed.
+    closed: bool,
+    /// The stream has been dropped, nothing will be consumed anymore.
+    dropped: bool,
+    /// Stream waiting for a push.
+    consumer: Option<Waker>,
+    /// Push waiting for room.
+    producer: Option<Waker>,
+}
+
+struct Readback<'a> {
+    /// `None` for empty buffers, nothing is copied.
+    copy: Option<(StagingLease<'a>, TimelineFuture)>,
+    len: usize,
+}
+
+impl<'a, D: Sized + Copy> ReadbackQueue<'a, D> {
+    pub fn new(app: &'a VulkanApp, depth: usize) -> (Self, ReadbackStream<'a, D>) {
+        assert!(
+            depth > 0,
+            "A readback queue needs room for at least one readback"
+        );
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let queue = Self {
+            app,
+            depth,
+            shared: Arc::clone(&shared),
+            _marker: Default::default(),
+        };
+        let stream = ReadbackStream {
+            shared,
+            _marker: Default::default(),
+        };
+        (queue, stream)
+    }
+
+    /// Readbacks submitted but not consumed yet.
+    pub fn len(&self) -> usize {
+        self.shared.lock().readbacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.depth
+    }
+
+    /// Wait for the stream to consume enough readbacks for the next [`push`](Self::push)
+    /// not to wait.
+    ///
+    /// Fails with [`VulkanError::StreamClosed`] if the stream has been dropped.
+    pub async fn ready(&mut self) -> Result<()> {
+        poll_fn(|ctx| {
+            let mut shared = self.shared.lock();
+            if shared.dropped {
+                Poll::Ready(Err(VulkanError::StreamClosed))
+            } else if shared.readbacks.len() < self.depth {
+                Poll::Ready(Ok(()))
+            } else {
+                shared.producer = Some(ctx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Copy the content of `buffer` once the submissions of `after` are executed,
+    /// it is yielded by the stream after everything pushed before.
+    ///
+    /// Waits for room first, see [`ready`](Self::ready).
+    pub async fn push<B: Buffer<Item = D>>(
+        &mut self,
+        buffer: &B,
+        after: &[&TimelineFuture],
+    ) -> Result<()> {
+        self.ready().await?;
+
+        // Empty copies are invalid, they still yield an empty Vec in order
+        let copy = if buffer.is_empty() {
+            None
+        } else {
+            Some(self.copy_to_staging(buffer, after)?)
+        };
+
+        let mut shared = self.shared.lock();
+        shared.readbacks.push_back(Readback {
+            copy,
+            len: buffer.len(),
+        });
+        if let Some(waker) = shared.consumer.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// End the stream once everything pushed so far has been consumed, same as dropping it.
+    pub fn close(self) {}
+
+    fn copy_to_staging<B: Buffer<Item = D>>(
+        &self,
+        buffer: &B,
+        after: &[&TimelineFuture],
+    ) -> Result<(StagingLease<'a>, TimelineFuture)> {
+        let size = buffer.size();
+        let mut staging = self.app.staging.acquire(&self.app.vma, size)?;
+
+        let mut commands = unsafe {
+            self.app
+                .record_commands(QueueKind::Transfer, |device, cmd| {
+                    let copy = vk::BufferCopy::builder()
+                        .size(size)
+                        .src_offset(buffer.byte_offset());
+                    device.cmd_copy_buffer(
+                        cmd,
+                        buffer.handle(),
+                        staging.buffer().handle,
+                        from_ref(&copy),
+                    );
+                    Ok(())
+                })?
+                .after(after)
+        };
+        commands.retain(buffer.used());
+        commands.own(owned_buffer(buffer));
+        let future = commands.submit()?;
+
+        staging.retire_after(&future);
+        Ok((staging, future))
+    }
+}
+
+impl<D> Drop for ReadbackQueue<'_, D> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.closed = true;
+        if let Some(waker) = shared.consumer.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<D> Drop for ReadbackStream<'_, D> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.dropped = true;
+        if let Some(waker) = shared.producer.take() {
+            waker.wake();
+        }
+    }
+}
+
+// Only reads values of `D` out of staging buffers, never holds one
+impl<D> Unpin for ReadbackStream<'_, D> {}
+
+impl<D: Sized + Copy> Stream for ReadbackStream<'_, D> {
+    type Item = Result<Vec<D>>;
+
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut guard = self.shared.lock();
+        let shared = &mut *guard;
+
+        let res = match shared.readbacks.front_mut() {
+            None if shared.closed => return Poll::Ready(None),
+            None => {
+                shared.consumer = Some(ctx.waker().clone());
+                return Poll::Pending;
+            }
+            Some(Readback { copy: None, .. }) => Ok(()),
+            Some(Readback {
+                copy: Some((_, future)),
+                ..
+            }) => match Pin::new(future).poll(ctx) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return Poll::Pending,
+            },
+        };
+
+        let readback = shared.readbacks.pop_front().unwrap();
+        if let Some(waker) = shared.producer.take() {
+            waker.wake();
+        }
+        drop(guard);
+
+        Poll::Ready(Some(res.and_then(|_| readback.read())))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let shared = self.shared.lock();
+        let len = shared.readbacks.len();
+        (len, shared.closed.then_some(len))
+    }
+}
+
+impl Readback<'_> {
+    fn read<D: Sized + Copy>(self) -> Result<Vec<D>> {
+        let mut out = Vec::with_capacity(self.len);
+        if let Some((staging, _)) = &self.copy {
+            staging.buffer().raw.read(
+                as_uninit_bytes_mut(&mut out.spare_capacity_mut()[..self.len]),
+                0,
+            )?;
+            // Every element has just been copied
+            unsafe { out.set_len(self.len) };
+        }
+        Ok(out)
+    }
+}
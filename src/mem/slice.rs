@@ -0,0 +1,105 @@
+use crate::{
+    mem::{private, Buffer},
+    tasks::{QueueOwner, Resource},
+};
+use ash::vk;
+use std::{
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+/// A range of elements of a buffer, usable anywhere a whole buffer is.
+///
+/// Obtained with [`Buffer::slice`], the offset and length are in elements, not bytes.
+pub struct BufferSlice<'a, D> {
+    parent: &'a (dyn private::Sealed + Sync),
+    handle: vk::Buffer,
+    offset: usize,
+    len: usize,
+    _marker: PhantomData<&'a [D]>,
+}
+
+impl<D> Clone for BufferSlice<'_, D> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<D> Copy for BufferSlice<'_, D> {}
+
+impl<D> private::Sealed for BufferSlice<'_, D> {
+    fn retained(&self) -> Resource {
+        self.parent.retained()
+    }
+
+    fn in_flight(&self) -> &private::InFlight {
+        self.parent.in_flight()
+    }
+
+    fn whole_size(&self) -> vk::DeviceSize {
+        self.parent.whole_size()
+    }
+
+    fn owner(&self) -> Option<&QueueOwner> {
+        self.parent.owner()
+    }
+}
+
+impl<D: Sized + Copy> Buffer for BufferSlice<'_, D> {
+    type Item = D;
+
+    fn handle(&self) -> vk::Buffer {
+        self.handle
+    }
+
+    fn size(&self) -> vk::DeviceSize {
+        (self.len * std::mem::size_of::<D>()) as _
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn byte_offset(&self) -> vk::DeviceSize {
+        (self.offset * std::mem::size_of::<D>()) as _
+    }
+}
+
+impl<'a, D: Sized + Copy> BufferSlice<'a, D> {
+    pub(crate) fn new<B: Buffer<Item = D> + Sync>(
+        buffer: &'a B,
+        range: impl RangeBounds<usize>,
+    ) -> Self {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => buffer.len(),
+        };
+        assert!(
+            start <= end && end <= buffer.len(),
+            "Range {}..{} is out of a buffer of {} elements",
+            start,
+            end,
+            buffer.len()
+        );
+
+        let offset = (buffer.byte_offset() / std::mem::size_of::<D>() as vk::DeviceSize) as usize;
+        Self {
+            parent: buffer,
+            handle: buffer.handle(),
+            offset: offset + start,
+            len: end - start,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Index of the first element in the whole buffer.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{
+    errors::Result,
+    mem::{create_mapped_buffer, RawAllocation},
+    tasks::TimelineFuture,
+};
+use ash::vk;
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Smallest staging buffer handed out by the pool.
+const STAGING_MIN_SIZE: vk::DeviceSize = 64 * 1024;
+
+/// Biggest pooled staging buffer, bigger transfers are split in chunks of this size.
+pub(crate) const STAGING_CHUNK_SIZE: vk::DeviceSize = 4 * 1024 * 1024;
+
+const STAGING_SIZE_CLASSES: usize =
+    (STAGING_CHUNK_SIZE / STAGING_MIN_SIZE).trailing_zeros() as usize + 1;
+
+/// How many idle buffers are kept around for each size class.
+const STAGING_MAX_FREE_PER_CLASS: usize = 4;
+
+/// Host visible, persistently mapped, buffer used to move data to and from the GPU.
+pub(crate) struct StagingBuffer {
+    pub(crate) handle: vk::Buffer,
+    pub(crate) raw: RawAllocation,
+    /// Size class in the pool, `None` if it is too big to be pooled.
+    class: Option<usize>,
+}
+
+impl Drop for StagingBuffer {
+    fn drop(&mut self) {
+        self.raw
+            .vma
+            .destroy_buffer(self.handle, &self.raw.allocation);
+    }
+}
+
+#[derive(Default)]
+struct PoolState {
+    free: [Vec<StagingBuffer>; STAGING_SIZE_CLASSES],
+}
+
+/// Recycles staging buffers by size class (powers of two) instead of allocating one per transfer.
+///
+/// The pool itself doesn't hold the allocator but its buffers do, idle ones included, and count
+/// as outstanding allocations until [`clear`](Self::clear) destroys them. The app clears it before
+/// destroying the allocator, once the submissions using them are complete.
+pub(crate) struct StagingPool {
+    /// Shared with the buffers still used by a submission, which come back once it completes.
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl StagingPool {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Arc::default(),
+        }
+    }
+
+    fn class_of(size: vk::DeviceSize) -> Option<usize> {
+        if size > STAGING_CHUNK_SIZE {
+            None
+        } else {
+            let size = size.max(STAGING_MIN_SIZE).next_power_of_two();
+            Some((size / STAGING_MIN_SIZE).trailing_zeros() as usize)
+        }
+    }
+
+    /// Get a staging buffer of at least `size` bytes, usable as both transfer source and destination.
+    pub(crate) fn acquire(
+        &self,
+        vma: &Arc<vk_mem::Allocator>,
+        size: vk::DeviceSize,
+    ) -> Result<StagingLease<'_>> {
+        let class = Self::class_of(size);
+
+        let recycled = class.and_then(|class| self.state.lock().free[class].pop());
+
+        let buffer = match recycled {
+            Some(buffer) => buffer,
+            None => {
+                let (handle, raw) = create_mapped_buffer(
+                    Arc::clone(vma),
+                    class.map_or(size, |class| STAGING_MIN_SIZE << class),
+                    vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST,
+                    vk_mem::MemoryUsage::CpuOnly,
+                    // Only used by the transfer queue
+                    &[],
+                )?;
+                StagingBuffer { handle, raw, class }
+            }
+        };
+
+        Ok(StagingLease {
+            pool: self,
+            buffer: Some(buffer),
+            after: None,
+        })
+    }
+
+    fn push_free(state: &mut PoolState, buffer: StagingBuffer) {
+        // Unpooled buffers are simply dropped
+        if let Some(class) = buffer.class {
+            if state.free[class].len() < STAGING_MAX_FREE_PER_CLASS {
+                state.free[class].push(buffer);
+            }
+        }
+    }
+
+    /// Destroy every idle buffer, the device must be idle.
+    pub(crate) fn clear(&self) {
+        for free in &mut self.state.lock().free {
+            free.clear();
+        }
+    }
+}
+
+/// A staging buffer borrowed from the pool.
+///
+/// It goes back to the pool when dropped, but only once the submission using it is done.
+/// Until then it is retained by the submission, so dropping a transfer midway never leaks it.
+pub(crate) struct StagingLease<'a> {
+    pool: &'a StagingPool,
+    buffer: Option<StagingBuffer>,
+    after: Option<TimelineFuture>,
+}
+
+impl StagingLease<'_> {
+    #[inline]
+    pub(crate) fn buffer(&self) -> &StagingBuffer {
+        self.buffer.as_ref().unwrap()
+    }
+
+    #[inline]
+    pub(crate) fn buffer_mut(&mut self) -> &mut StagingBuffer {
+        self.buffer.as_mut().unwrap()
+    }
+
+    /// Don't recycle the buffer before this submission is complete.
+    pub(crate) fn retire_after(&mut self, future: &TimelineFuture) {
+        self.after = Some(future.clone());
+    }
+}
+
+impl Drop for StagingLease<'_> {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            let returning = InFlightStaging {
+                state: Arc::clone(&self.pool.state),
+                buffer: Some(buffer),
+            };
+            match self.after.take() {
+                Some(future) if !future.is_complete() => future.retain(Arc::new(returning)),
+                _ => drop(returning),
+            }
+        }
+    }
+}
+
+/// Gives a staging buffer back to the pool when dropped, once the submission using it is done.
+struct InFlightStaging {
+    state: Arc<Mutex<PoolState>>,
+    buffer: Option<StagingBuffer>,
+}
+
+impl Drop for InFlightStaging {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            StagingPool::push_free(&mut self.state.lock(), buffer);
+        }
+    }
+}
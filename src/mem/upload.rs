@@ -0,0 +1,206 @@
+use crate::{
+    errors::{Result, VulkanError},
+    mem::{owned_buffer, private::Sealed, Buffer, GpuBufferHandle, StagingLease},
+    setup::QueueKind,
+    tasks::TimelineFuture,
+    VulkanApp,
+};
+use ash::vk;
+use futures::Sink;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    ops::Range,
+    pin::Pin,
+    slice::from_ref,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Streams data into a GPU buffer, each item being written right after the previous one.
+///
+/// Items go through pooled staging buffers instead of a new buffer per upload, at most `depth`
+/// transfers are in flight and the sink waits for the oldest one to complete before accepting more.
+///
+/// The buffer is used as a ring, an item that doesn't fit at the end is written at the start.
+/// Transfers after that wait on the GPU for those made before wrapping, which they may overwrite.
+/// Commands reading the uploaded data aren't known to the sink, declare them with
+/// [`release_after`](Self::release_after) or the ring may overwrite what they are still reading.
+///
+/// ```ignore
+/// let mut uploads = UploadSink::new(&app, &mut samples, 4);
+/// while let Some(batch) = sensor.next().await {
+///     uploads.send(&batch).await?;
+///     let written = uploads.last_written().unwrap();
+///     let compute = app
+///         .record(QueueKind::Compute, |rec| ...)?
+///         .after(&[uploads.last_upload().unwrap()])
+///         .submit()?;
+///     uploads.release_after(&compute);
+/// }
+/// uploads.close().await?;
+/// ```
+pub struct UploadSink<'a, D> {
+    app: &'a VulkanApp,
+    buffer: &'a mut GpuBufferHandle<D>,
+    depth: usize,
+    /// Element where the next item is written, unless it has to wrap.
+    position: usize,
+    in_flight: VecDeque<(StagingLease<'a>, TimelineFuture)>,
+    /// Elements written by the last item.
+    last_written: Option<Range<usize>>,
+    /// Commands reading what has been written since the last wrap, the last one of each queue.
+    consumers: Vec<TimelineFuture>,
+    /// Last transfer and consumers before wrapping to the start, until they are done.
+    before_wrap: Vec<TimelineFuture>,
+}
+
+impl<'a, D: Sized + Copy> UploadSink<'a, D> {
+    pub fn new(app: &'a VulkanApp, buffer: &'a mut GpuBufferHandle<D>, depth: usize) -> Self {
+        assert!(
+            depth > 0,
+            "An upload sink needs room for at least one transfer"
+        );
+        Self {
+            app,
+            buffer,
+            depth,
+            position: 0,
+            in_flight: VecDeque::with_capacity(depth),
+            last_written: None,
+            consumers: Vec::new(),
+            before_wrap: Vec::new(),
+        }
+    }
+
+    /// Element where the next item will be written if it fits before the end of the buffer,
+    /// at the start otherwise, see [`offset_for`](Self::offset_for).
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Element where the next item will be written if it is `len` elements long.
+    pub fn offset_for(&self, len: usize) -> usize {
+        if self.wraps(len) {
+            0
+        } else {
+            self.position
+        }
+    }
+
+    /// Elements written by the last item sent.
+    pub fn last_written(&self) -> Option<Range<usize>> {
+        self.last_written.clone()
+    }
+
+    /// Don't overwrite what has been uploaded so far before `consumer` completes,
+    /// e.g. a dispatch reading it.
+    ///
+    /// Only transfers after the ring wraps wait for it, so it must be declared before the sink
+    /// wraps past the data it reads.
+    pub fn release_after(&mut self, consumer: &TimelineFuture) {
+        match self
+            .consumers
+            .iter_mut()
+            .find(|c| Arc::ptr_eq(c.timeline(), consumer.timeline()))
+        {
+            Some(c) if c.value() < consumer.value() => *c = consumer.clone(),
+            Some(_) => {}
+            None => self.consumers.push(consumer.clone()),
+        }
+    }
+
+    fn wraps(&self, len: usize) -> bool {
+        self.position + len > self.buffer.len()
+    }
+
+    /// The last transfer submitted, e.g. to wait for it on the GPU before using the data.
+    pub fn last_upload(&self) -> Option<&TimelineFuture> {
+        self.in_flight.back().map(|(_, future)| future)
+    }
+
+    /// Forget the completed transfers, until one is still pending or only `keep` are left.
+    fn poll_completed(&mut self, ctx: &mut Context<'_>, keep: usize) -> Poll<Result<()>> {
+        while self.in_flight.len() > keep {
+            let (_, future) = self.in_flight.front_mut().unwrap();
+            match Pin::new(future).poll(ctx) {
+                Poll::Ready(res) => {
+                    self.in_flight.pop_front();
+                    res?;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Only writes values of `D` into staging buffers, never holds one
+impl<D> Unpin for UploadSink<'_, D> {}
+
+impl<D: Sized + Copy> Sink<&[D]> for UploadSink<'_, D> {
+    type Error = VulkanError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Result<()>> {
+        let keep = self.depth - 1;
+        self.poll_completed(ctx, keep)
+    }
+
+    /// Panics if the item is bigger than the whole buffer.
+    fn start_send(mut self: Pin<&mut Self>, item: &[D]) -> Result<()> {
+        assert!(
+            item.len() <= self.buffer.len(),
+            "Item of {} elements doesn't fit in the buffer",
+            item.len()
+        );
+        if item.is_empty() {
+            return Ok(());
+        }
+
+        if self.wraps(item.len()) {
+            self.position = 0;
+            let last_upload = self.last_upload().cloned();
+            self.before_wrap = self.consumers.drain(..).chain(last_upload).collect();
+        }
+        self.before_wrap.retain(|future| !future.is_complete());
+
+        let size = std::mem::size_of_val(item) as vk::DeviceSize;
+        let dst_offset = (self.position * std::mem::size_of::<D>()) as vk::DeviceSize;
+        let mut staging = self.app.staging.acquire(&self.app.vma, size)?;
+        staging.buffer_mut().raw.write_to(item)?;
+
+        let mut commands = unsafe {
+            self.app
+                .record_commands(QueueKind::Transfer, |device, cmd| {
+                    let copy = vk::BufferCopy::builder().size(size).dst_offset(dst_offset);
+                    device.cmd_copy_buffer(
+                        cmd,
+                        staging.buffer().handle,
+                        self.buffer.handle(),
+                        from_ref(&copy),
+                    );
+                    Ok(())
+                })?
+        };
+        if !self.before_wrap.is_empty() {
+            commands = commands.after(&self.before_wrap.iter().collect::<Vec<_>>());
+        }
+        commands.retain(self.buffer.used());
+        commands.own(owned_buffer(&*self.buffer));
+        let future = commands.submit()?;
+
+        staging.retire_after(&future);
+        self.in_flight.push_back((staging, future));
+        self.last_written = Some(self.position..self.position + item.len());
+        self.position += item.len();
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_completed(ctx, 0)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(ctx)
+    }
+}
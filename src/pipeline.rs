@@ -0,0 +1,24 @@
+use crate::errors::{Result, VulkanError};
+use ash::vk;
+
+mod compute;
+pub use compute::*;
+
+mod descriptors;
+pub use descriptors::*;
+
+mod reflect;
+pub use reflect::*;
+
+/// Read SPIR-V bytes into words, the bytes don't need to be aligned.
+pub(crate) fn read_spirv(spirv: &[u8]) -> Result<Vec<u32>> {
+    ash::util::read_spv(&mut std::io::Cursor::new(spirv))
+        .map_err(|e| VulkanError::InvalidSpirv(e.to_string()))
+}
+
+pub(crate) unsafe fn create_shader_module(
+    device: &ash::Device,
+    code: &[u32],
+) -> Result<vk::ShaderModule> {
+    Ok(device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)?)
+}
@@ -0,0 +1,174 @@
+use crate::{
+    errors::{Result, VulkanError},
+    pipeline::{
+        create_shader_module, read_spirv, DescriptorSet, DescriptorSetBuilder, ShaderReflection,
+    },
+    setup::QueueKind,
+    VulkanApp,
+};
+use ash::vk;
+use std::{ffi::CString, slice::from_ref, sync::Arc};
+
+/// A compute shader ready to be dispatched on the compute queue.
+///
+/// Its pipeline layout is derived from the SPIR-V itself, see [`ShaderReflection`].
+pub struct ComputePipeline {
+    pub(crate) app: Arc<VulkanApp>,
+    pub(crate) objects: Arc<PipelineObjects>,
+    pub(crate) reflection: ShaderReflection,
+}
+
+/// The Vulkan objects of a pipeline, also kept alive by the submissions using them.
+pub(crate) struct PipelineObjects {
+    device: ash::Device,
+    pub(crate) pipeline: vk::Pipeline,
+    pub(crate) layout: vk::PipelineLayout,
+    pub(crate) set_layouts: Vec<vk::DescriptorSetLayout>,
+}
+
+impl Drop for PipelineObjects {
+    fn drop(&mut self) {
+        unsafe {
+            let device = &self.device;
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_pipeline_layout(self.layout, None);
+            for layout in &self.set_layouts {
+                device.destroy_descriptor_set_layout(*layout, None);
+            }
+        }
+    }
+}
+
+impl ComputePipeline {
+    pub(crate) fn new(app: Arc<VulkanApp>, spirv: &[u8], entry_point: &str) -> Result<Self> {
+        let code = read_spirv(spirv)?;
+        let reflection = ShaderReflection::from_spirv(&code, entry_point)?;
+        if reflection.stage != vk::ShaderStageFlags::COMPUTE {
+            return Err(VulkanError::InvalidSpirv(format!(
+                "{} isn't a compute shader",
+                entry_point
+            )));
+        }
+        let entry_point = CString::new(entry_point).map_err(|_| {
+            VulkanError::InvalidSpirv(format!("{:?} isn't a valid entry point name", entry_point))
+        })?;
+        let device = &app.device;
+
+        unsafe {
+            let set_layouts = reflection.create_set_layouts(device)?;
+            let destroy_set_layouts = || {
+                for layout in &set_layouts {
+                    device.destroy_descriptor_set_layout(*layout, None);
+                }
+            };
+
+            let push_constants = vk::PushConstantRange::builder()
+                .stage_flags(reflection.stage)
+                .offset(0)
+                .size(reflection.push_constants_size);
+            let push_constants = if reflection.push_constants_size > 0 {
+                from_ref(&*push_constants)
+            } else {
+                &[]
+            };
+
+            let layout = match device.create_pipeline_layout(
+                &vk::PipelineLayoutCreateInfo::builder()
+                    .set_layouts(&set_layouts)
+                    .push_constant_ranges(push_constants),
+                None,
+            ) {
+                Ok(layout) => layout,
+                Err(e) => {
+                    destroy_set_layouts();
+                    return Err(e.into());
+                }
+            };
+
+            let pipeline = create_shader_module(device, &code).and_then(|module| {
+                let res = device.create_compute_pipelines(
+                    vk::PipelineCache::null(),
+                    from_ref(
+                        &vk::ComputePipelineCreateInfo::builder()
+                            .stage(
+                                vk::PipelineShaderStageCreateInfo::builder()
+                                    .stage(vk::ShaderStageFlags::COMPUTE)
+                                    .module(module)
+                                    .name(&entry_point)
+                                    .build(),
+                            )
+                            .layout(layout),
+                    ),
+                    None,
+                );
+                device.destroy_shader_module(module, None);
+
+                Ok(res.map_err(|(_, e)| e)?[0])
+            });
+
+            let pipeline = match pipeline {
+                Ok(pipeline) => pipeline,
+                Err(e) => {
+                    device.destroy_pipeline_layout(layout, None);
+                    destroy_set_layouts();
+                    return Err(e);
+                }
+            };
+
+            Ok(Self {
+                objects: Arc::new(PipelineObjects {
+                    device: device.clone(),
+                    pipeline,
+                    layout,
+                    set_layouts,
+                }),
+                app,
+                reflection,
+            })
+        }
+    }
+
+    /// What has been read from the SPIR-V of the shader.
+    pub fn reflection(&self) -> &ShaderReflection {
+        &self.reflection
+    }
+
+    /// Local size declared by the shader.
+    pub fn workgroup_size(&self) -> [u32; 3] {
+        self.reflection.workgroup_size.unwrap_or([1, 1, 1])
+    }
+
+    /// Amount of workgroups needed to cover at least this many invocations.
+    pub fn workgroups_for(&self, invocations: [u32; 3]) -> [u32; 3] {
+        let size = self.workgroup_size();
+        [
+            invocations[0].div_ceil(size[0]),
+            invocations[1].div_ceil(size[1]),
+            invocations[2].div_ceil(size[2]),
+        ]
+    }
+
+    /// Start describing the resources to bind to the given set of this pipeline.
+    pub fn descriptor_set(&self, set: u32) -> DescriptorSetBuilder<'_> {
+        DescriptorSetBuilder::new(self, set)
+    }
+
+    /// Run the shader with the given amount of workgroups and wait for it to finish.
+    pub async fn dispatch(&self, sets: &[&DescriptorSet], x: u32, y: u32, z: u32) -> Result<()> {
+        self.app
+            .record(QueueKind::Compute, |rec| rec.dispatch(self, sets, x, y, z))?
+            .submit()?
+            .await
+    }
+}
+
+impl VulkanApp {
+    /// Create a compute pipeline from SPIR-V, its layout is deduced from the shader.
+    pub fn create_compute_pipeline(
+        self: &Arc<Self>,
+        spirv: &[u8],
+        entry_point: &str,
+    ) -> Result<ComputePipeline> {
+        ComputePipeline::new(Arc::clone(self), spirv, entry_point)
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{
+    errors::{Result, VulkanError},
+    mem::{owned_buffer, private::InFlight, Buffer},
+    pipeline::ComputePipeline,
+    tasks::{Access, BufferAccess, Owned, Resource},
+    VulkanApp,
+};
+use ash::vk;
+use std::{slice::from_ref, sync::Arc};
+
+/// Resources bound to one set of a pipeline, to be used when dispatching it.
+///
+/// Never updated after creation so it can be used by several dispatches in flight.
+pub struct DescriptorSet {
+    _app: Arc<VulkanApp>,
+    pub(crate) objects: Arc<DescriptorSetObjects>,
+    pub(crate) set: vk::DescriptorSet,
+    pub(crate) index: u32,
+    pub(crate) layout: vk::DescriptorSetLayout,
+    /// How dispatches access the bound buffers.
+    pub(crate) accesses: Vec<BufferAccess>,
+    /// Counters of the bound buffers, only in flight while a dispatch uses them.
+    pub(crate) in_flight: Vec<InFlight>,
+    /// Bound `EXCLUSIVE` buffers, owned by the queue of the dispatches.
+    pub(crate) owned: Vec<Owned>,
+}
+
+/// Owns the pool of a set and keeps the bound buffers alive, as long as a submission uses it.
+pub(crate) struct DescriptorSetObjects {
+    device: ash::Device,
+    pool: vk::DescriptorPool,
+    _buffers: Vec<Resource>,
+}
+
+impl Drop for DescriptorSetObjects {
+    fn drop(&mut self) {
+        unsafe {
+            // Also frees the set
+            self.device.destroy_descriptor_pool(self.pool, None);
+        }
+    }
+}
+
+impl DescriptorSet {
+    /// Index of the set in the pipeline layout.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+pub struct DescriptorSetBuilder<'a> {
+    pipeline: &'a ComputePipeline,
+    set: u32,
+    buffers: Vec<(
+        u32,
+        vk::DescriptorType,
+        vk::DescriptorBufferInfo,
+        Resource,
+        InFlight,
+        Option<Owned>,
+    )>,
+}
+
+impl<'a> DescriptorSetBuilder<'a> {
+    pub(crate) fn new(pipeline: &'a ComputePipeline, set: u32) -> Self {
+        Self {
+            pipeline,
+            set,
+            buffers: Vec::new(),
+        }
+    }
+
+    /// Bind a buffer, or a slice of one, as the storage buffer at `binding`.
+    pub fn storage_buffer<B: Buffer>(self, binding: u32, buffer: &B) -> Self {
+        self.buffer(binding, vk::DescriptorType::STORAGE_BUFFER, buffer)
+    }
+
+    /// Bind a buffer, or a slice of one, as the uniform buffer at `binding`.
+    pub fn uniform_buffer<B: Buffer>(self, binding: u32, buffer: &B) -> Self {
+        self.buffer(binding, vk::DescriptorType::UNIFORM_BUFFER, buffer)
+    }
+
+    fn buffer<B: Buffer>(mut self, binding: u32, ty: vk::DescriptorType, buffer: &B) -> Self {
+        self.buffers.retain(|(b, _, _, _, _, _)| *b != binding);
+        self.buffers.push((
+            binding,
+            ty,
+            vk::DescriptorBufferInfo::builder()
+                .buffer(buffer.handle())
+                .offset(buffer.byte_offset())
+                .range(buffer.size())
+                .build(),
+            buffer.retained(),
+            buffer.in_flight().clone(),
+            owned_buffer(buffer),
+        ));
+        self
+    }
+
+    /// Check what has been bound against what the shader declares.
+    fn validate(&self) -> Result<()> {
+        let reflection = &self.pipeline.reflection;
+        let mismatch = |msg: String| Err(VulkanError::InvalidDescriptorSet(msg));
+
+        if self.set >= reflection.set_count() {
+            return mismatch(format!("the shader has no set {}", self.set));
+        }
+
+        let limits = self
+            .pipeline
+            .app
+            .vma
+            .get_physical_device_properties()?
+            .limits;
+        for (binding, ty, info, _, _, _) in &self.buffers {
+            if info.range == 0 {
+                return mismatch(format!("binding {} is an empty buffer", binding));
+            }
+            let alignment = if *ty == vk::DescriptorType::UNIFORM_BUFFER {
+                limits.min_uniform_buffer_offset_alignment
+            } else {
+                limits.min_storage_buffer_offset_alignment
+            };
+            if info.offset % alignment != 0 {
+                return mismatch(format!(
+                    "binding {} starts at byte {}, not a multiple of {}",
+                    binding, info.offset, alignment
+                ));
+            }
+
+            match reflection
+                .set_bindings(self.set)
+                .find(|b| b.binding == *binding)
+            {
+                None => return mismatch(format!("the shader has no binding {}", binding)),
+                Some(declared) if declared.ty != *ty => {
+                    return mismatch(format!(
+                        "binding {} is a {:?} in the shader, not a {:?}",
+                        binding, declared.ty, ty
+                    ))
+                }
+                Some(declared) if declared.count != 1 => {
+                    return mismatch(format!("binding {} is an array", binding))
+                }
+                _ => {}
+            }
+        }
+
+        for declared in reflection.set_bindings(self.set) {
+            if !self
+                .buffers
+                .iter()
+                .any(|(b, _, _, _, _, _)| *b == declared.binding)
+            {
+                return mismatch(format!("binding {} is left unbound", declared.binding));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<DescriptorSet> {
+        self.validate()?;
+
+        let app = &self.pipeline.app;
+        let layout = self.pipeline.objects.set_layouts[self.set as usize];
+
+        let mut pool_sizes = self
+            .buffers
+            .iter()
+            .map(|(_, ty, _, _, _, _)| {
+                vk::DescriptorPoolSize::builder()
+                    .ty(*ty)
+                    .descriptor_count(1)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        // A set can have no bindings, but a pool can't be empty
+        if pool_sizes.is_empty() {
+            pool_sizes.push(
+                vk::DescriptorPoolSize::builder()
+                    .ty(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .build(),
+            );
+        }
+
+        unsafe {
+            let pool = app.device.create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::builder()
+                    .max_sets(1)
+                    .pool_sizes(&pool_sizes),
+                None,
+            )?;
+
+            let set = match app.device.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(pool)
+                    .set_layouts(from_ref(&layout)),
+            ) {
+                Ok(sets) => sets[0],
+                Err(e) => {
+                    app.device.destroy_descriptor_pool(pool, None);
+                    return Err(e.into());
+                }
+            };
+
+            let writes = self
+                .buffers
+                .iter()
+                .map(|(binding, ty, info, _, _, _)| {
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(*binding)
+                        .descriptor_type(*ty)
+                        .buffer_info(from_ref(info))
+                        .build()
+                })
+                .collect::<Vec<_>>();
+            app.device.update_descriptor_sets(&writes, &[]);
+
+            let accesses = self
+                .buffers
+                .iter()
+                .map(|(_, ty, info, _, _, _)| BufferAccess {
+                    handle: info.buffer,
+                    offset: info.offset,
+                    size: info.range,
+                    // Storage buffers are assumed to be written
+                    access: if *ty == vk::DescriptorType::UNIFORM_BUFFER {
+                        Access::COMPUTE_UNIFORM_READ
+                    } else {
+                        Access::COMPUTE_SHADER_READ | Access::COMPUTE_SHADER_WRITE
+                    },
+                })
+                .collect();
+            let in_flight = self
+                .buffers
+                .iter()
+                .map(|(_, _, _, _, in_flight, _)| in_flight.clone())
+                .collect();
+            let owned = self
+                .buffers
+                .iter()
+                .filter_map(|(_, _, _, _, _, owned)| owned.clone())
+                .collect();
+
+            Ok(DescriptorSet {
+                _app: Arc::clone(app),
+                objects: Arc::new(DescriptorSetObjects {
+                    device: app.device.clone(),
+                    pool,
+                    _buffers: self
+                        .buffers
+                        .into_iter()
+                        .map(|(_, _, _, r, _, _)| r)
+                        .collect(),
+                }),
+                set,
+                index: self.set,
+                layout,
+                accesses,
+                in_flight,
+                owned,
+            })
+        }
+    }
+}
@@ -0,0 +1,775 @@
+use crate::errors::{Result, VulkanError};
+use ash::vk;
+use std::collections::HashMap;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+const SPIRV_HEADER_LEN: usize = 5;
+/// Deepest nesting of types whose size is computed, well formed modules can't have cycles.
+const MAX_TYPE_DEPTH: u32 = 64;
+
+// Opcodes
+const OP_ENTRY_POINT: u32 = 15;
+const OP_EXECUTION_MODE: u32 = 16;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_SPEC_CONSTANT: u32 = 50;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+const OP_EXECUTION_MODE_ID: u32 = 331;
+const OP_TYPE_ACCELERATION_STRUCTURE: u32 = 5341;
+
+// Decorations
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+// Storage classes
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+// Execution modes
+const MODE_LOCAL_SIZE: u32 = 17;
+const MODE_LOCAL_SIZE_ID: u32 = 38;
+
+// Image dimensions
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+/// A descriptor used by a shader, as declared in its SPIR-V.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub ty: vk::DescriptorType,
+    pub count: u32,
+}
+
+/// What a shader expects from its pipeline layout, read from its SPIR-V.
+#[derive(Debug, Clone)]
+pub struct ShaderReflection {
+    pub stage: vk::ShaderStageFlags,
+    /// Sorted by set then binding.
+    pub bindings: Vec<DescriptorBinding>,
+    /// Size in bytes of the push constant block, 0 if there is none.
+    pub push_constants_size: u32,
+    /// Only for compute shaders.
+    pub workgroup_size: Option<[u32; 3]>,
+}
+
+#[derive(Debug, Clone)]
+enum Type {
+    Scalar { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray,
+    Struct { members: Vec<u32> },
+    Pointer { storage: u32, pointee: u32 },
+    AccelerationStructure,
+}
+
+#[derive(Default)]
+struct Module {
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    /// (id, decoration) -> first literal
+    decorations: HashMap<(u32, u32), u32>,
+    /// (struct id, member, decoration) -> first literal
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+    /// (pointer type, variable id)
+    variables: Vec<(u32, u32)>,
+    /// (execution model, id, name)
+    entry_points: Vec<(u32, u32, String)>,
+    /// (entry point id, mode, operands, whether the operands are ids)
+    execution_modes: Vec<(u32, u32, Vec<u32>, bool)>,
+}
+
+fn invalid(msg: impl Into<String>) -> VulkanError {
+    VulkanError::InvalidSpirv(msg.into())
+}
+
+/// Decode a nul terminated string literal.
+fn parse_string(words: &[u32]) -> String {
+    let bytes = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .take_while(|byte| *byte != 0)
+        .collect::<Vec<_>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn execution_model_to_stage(model: u32) -> vk::ShaderStageFlags {
+    match model {
+        0 => vk::ShaderStageFlags::VERTEX,
+        1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        3 => vk::ShaderStageFlags::GEOMETRY,
+        4 => vk::ShaderStageFlags::FRAGMENT,
+        5 => vk::ShaderStageFlags::COMPUTE,
+        _ => vk::ShaderStageFlags::ALL,
+    }
+}
+
+impl Module {
+    fn parse(words: &[u32]) -> Result<Self> {
+        if words.len() < SPIRV_HEADER_LEN || words[0] != SPIRV_MAGIC {
+            return Err(invalid("missing SPIR-V header"));
+        }
+
+        let mut module = Self::default();
+        let mut cursor = SPIRV_HEADER_LEN;
+
+        while cursor < words.len() {
+            let word_count = (words[cursor] >> 16) as usize;
+            let opcode = words[cursor] & 0xffff;
+            if word_count == 0 || cursor + word_count > words.len() {
+                return Err(invalid("truncated instruction"));
+            }
+
+            let ops = &words[cursor + 1..cursor + word_count];
+            let op = |i: usize| {
+                ops.get(i)
+                    .copied()
+                    .ok_or_else(|| invalid(format!("missing operand for opcode {}", opcode)))
+            };
+
+            match opcode {
+                OP_ENTRY_POINT => {
+                    let name = parse_string(&ops[2.min(ops.len())..]);
+                    module.entry_points.push((op(0)?, op(1)?, name));
+                }
+                OP_EXECUTION_MODE | OP_EXECUTION_MODE_ID => {
+                    module.execution_modes.push((
+                        op(0)?,
+                        op(1)?,
+                        ops[2.min(ops.len())..].to_vec(),
+                        opcode == OP_EXECUTION_MODE_ID,
+                    ));
+                }
+                OP_TYPE_BOOL => {
+                    module.types.insert(op(0)?, Type::Scalar { width: 32 });
+                }
+                OP_TYPE_INT | OP_TYPE_FLOAT => {
+                    module.types.insert(op(0)?, Type::Scalar { width: op(1)? });
+                }
+                OP_TYPE_VECTOR => {
+                    let ty = Type::Vector {
+                        component: op(1)?,
+                        count: op(2)?,
+                    };
+                    module.types.insert(op(0)?, ty);
+                }
+                OP_TYPE_MATRIX => {
+                    let ty = Type::Matrix {
+                        column: op(1)?,
+                        count: op(2)?,
+                    };
+                    module.types.insert(op(0)?, ty);
+                }
+                OP_TYPE_IMAGE => {
+                    let ty = Type::Image {
+                        dim: op(2)?,
+                        sampled: op(6)?,
+                    };
+                    module.types.insert(op(0)?, ty);
+                }
+                OP_TYPE_SAMPLER => {
+                    module.types.insert(op(0)?, Type::Sampler);
+                }
+                OP_TYPE_SAMPLED_IMAGE => {
+                    module.types.insert(op(0)?, Type::SampledImage);
+                }
+                OP_TYPE_ARRAY => {
+                    // The length is a constant that has been declared before
+                    let length = *module
+                        .constants
+                        .get(&op(2)?)
+                        .ok_or_else(|| invalid("array length isn't a known constant"))?;
+                    let ty = Type::Array {
+                        element: op(1)?,
+                        length,
+                    };
+                    module.types.insert(op(0)?, ty);
+                }
+                OP_TYPE_RUNTIME_ARRAY => {
+                    module.types.insert(op(0)?, Type::RuntimeArray);
+                }
+                OP_TYPE_STRUCT => {
+                    let ty = Type::Struct {
+                        members: ops[1.min(ops.len())..].to_vec(),
+                    };
+                    module.types.insert(op(0)?, ty);
+                }
+                OP_TYPE_POINTER => {
+                    let ty = Type::Pointer {
+                        storage: op(1)?,
+                        pointee: op(2)?,
+                    };
+                    module.types.insert(op(0)?, ty);
+                }
+                OP_TYPE_ACCELERATION_STRUCTURE => {
+                    module.types.insert(op(0)?, Type::AccelerationStructure);
+                }
+                OP_CONSTANT | OP_SPEC_CONSTANT => {
+                    // Only the low bits matter for sizes and lengths
+                    module.constants.insert(op(1)?, op(2)?);
+                }
+                OP_VARIABLE => {
+                    module.variables.push((op(0)?, op(1)?));
+                }
+                OP_DECORATE => {
+                    let literal = ops.get(2).copied().unwrap_or(0);
+                    module.decorations.insert((op(0)?, op(1)?), literal);
+                }
+                OP_MEMBER_DECORATE => {
+                    let literal = ops.get(3).copied().unwrap_or(0);
+                    module
+                        .member_decorations
+                        .insert((op(0)?, op(1)?, op(2)?), literal);
+                }
+                _ => {}
+            }
+
+            cursor += word_count;
+        }
+
+        Ok(module)
+    }
+
+    fn ty(&self, id: u32) -> Result<&Type> {
+        self.types
+            .get(&id)
+            .ok_or_else(|| invalid(format!("unknown type %{}", id)))
+    }
+
+    fn decoration(&self, id: u32, decoration: u32) -> Option<u32> {
+        self.decorations.get(&(id, decoration)).copied()
+    }
+
+    /// Size in bytes of a type laid out with explicit offsets and strides.
+    fn size_of(&self, id: u32) -> Result<u32> {
+        self.size_at_depth(id, 0)
+    }
+
+    fn size_at_depth(&self, id: u32, depth: u32) -> Result<u32> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(invalid(format!("type %{} is nested too deeply", id)));
+        }
+        let too_big = || invalid(format!("type %{} is too big", id));
+        let size_of = |id: u32| self.size_at_depth(id, depth + 1);
+
+        match self.ty(id)? {
+            Type::Scalar { width } => Ok(width / 8),
+            Type::Vector { component, count } => {
+                size_of(*component)?.checked_mul(*count).ok_or_else(too_big)
+            }
+            Type::Matrix { column, count } => {
+                size_of(*column)?.checked_mul(*count).ok_or_else(too_big)
+            }
+            Type::Array { element, length } => {
+                let stride = match self.decoration(id, DECORATION_ARRAY_STRIDE) {
+                    Some(stride) => stride,
+                    None => size_of(*element)?,
+                };
+                stride.checked_mul(*length).ok_or_else(too_big)
+            }
+            Type::Struct { members } => {
+                let mut size = 0u32;
+                for (i, member) in members.iter().enumerate() {
+                    let offset = self
+                        .member_decorations
+                        .get(&(id, i as u32, DECORATION_OFFSET))
+                        .copied()
+                        .unwrap_or(size);
+                    let member_size = match (
+                        self.ty(*member)?,
+                        self.member_decorations
+                            .get(&(id, i as u32, DECORATION_MATRIX_STRIDE)),
+                    ) {
+                        (Type::Matrix { count, .. }, Some(stride)) => {
+                            stride.checked_mul(*count).ok_or_else(too_big)?
+                        }
+                        _ => size_of(*member)?,
+                    };
+                    let end = offset.checked_add(member_size).ok_or_else(too_big)?;
+                    size = size.max(end);
+                }
+                Ok(size)
+            }
+            _ => Err(invalid(format!("type %{} has no size", id))),
+        }
+    }
+
+    /// Descriptor type and count of what a resource variable points to.
+    fn descriptor_of(&self, storage: u32, pointee: u32) -> Result<(vk::DescriptorType, u32)> {
+        let (pointee, count) = match self.ty(pointee)? {
+            Type::Array { element, length } => (*element, *length),
+            Type::RuntimeArray => {
+                return Err(invalid("runtime arrays of descriptors are not supported"))
+            }
+            _ => (pointee, 1),
+        };
+
+        let ty = match (storage, self.ty(pointee)?) {
+            (STORAGE_STORAGE_BUFFER, _) => vk::DescriptorType::STORAGE_BUFFER,
+            (STORAGE_UNIFORM, _) if self.decoration(pointee, DECORATION_BUFFER_BLOCK).is_some() => {
+                vk::DescriptorType::STORAGE_BUFFER
+            }
+            (STORAGE_UNIFORM, _) if self.decoration(pointee, DECORATION_BLOCK).is_some() => {
+                vk::DescriptorType::UNIFORM_BUFFER
+            }
+            (STORAGE_UNIFORM_CONSTANT, Type::Sampler) => vk::DescriptorType::SAMPLER,
+            (STORAGE_UNIFORM_CONSTANT, Type::SampledImage) => {
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+            }
+            (STORAGE_UNIFORM_CONSTANT, Type::Image { dim, sampled }) => match (*dim, *sampled) {
+                (DIM_BUFFER, 2) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                (DIM_BUFFER, _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                (DIM_SUBPASS_DATA, _) => vk::DescriptorType::INPUT_ATTACHMENT,
+                (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+                _ => vk::DescriptorType::SAMPLED_IMAGE,
+            },
+            (STORAGE_UNIFORM_CONSTANT, Type::AccelerationStructure) => {
+                vk::DescriptorType::ACCELERATION_STRUCTURE_KHR
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "can't deduce descriptor type of %{}",
+                    pointee
+                )))
+            }
+        };
+
+        Ok((ty, count))
+    }
+}
+
+impl ShaderReflection {
+    /// Read the resources used by a SPIR-V module and the properties of one of its entry points.
+    pub fn from_spirv(words: &[u32], entry_point: &str) -> Result<Self> {
+        let module = Module::parse(words)?;
+
+        let (model, entry_id) = module
+            .entry_points
+            .iter()
+            .find(|(_, _, name)| name == entry_point)
+            .map(|(model, id, _)| (*model, *id))
+            .ok_or_else(|| invalid(format!("no entry point named {}", entry_point)))?;
+
+        let mut bindings = Vec::new();
+        let mut push_constants_size = 0;
+
+        for (pointer, variable) in &module.variables {
+            let (storage, pointee) = match module.ty(*pointer)? {
+                Type::Pointer { storage, pointee } => (*storage, *pointee),
+                _ => return Err(invalid("variable isn't a pointer")),
+            };
+
+            match storage {
+                STORAGE_PUSH_CONSTANT => {
+                    push_constants_size = push_constants_size.max(module.size_of(pointee)?);
+                }
+                STORAGE_UNIFORM_CONSTANT | STORAGE_UNIFORM | STORAGE_STORAGE_BUFFER => {
+                    let set = module.decoration(*variable, DECORATION_DESCRIPTOR_SET);
+                    let binding = module.decoration(*variable, DECORATION_BINDING);
+
+                    if let (Some(set), Some(binding)) = (set, binding) {
+                        let (ty, count) = module.descriptor_of(storage, pointee)?;
+                        bindings.push(DescriptorBinding {
+                            set,
+                            binding,
+                            ty,
+                            count,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        bindings.sort_by_key(|b| (b.set, b.binding));
+
+        let mut workgroup_size = None;
+        for (entry, mode, operands, is_id) in &module.execution_modes {
+            if *entry != entry_id || operands.len() < 3 {
+                continue;
+            }
+
+            match (*mode, *is_id) {
+                (MODE_LOCAL_SIZE, false) => {
+                    workgroup_size = Some([operands[0], operands[1], operands[2]]);
+                }
+                (MODE_LOCAL_SIZE_ID, true) => {
+                    let constant = |id: u32| {
+                        module
+                            .constants
+                            .get(&id)
+                            .copied()
+                            .ok_or_else(|| invalid("workgroup size isn't a known constant"))
+                    };
+                    workgroup_size = Some([
+                        constant(operands[0])?,
+                        constant(operands[1])?,
+                        constant(operands[2])?,
+                    ]);
+                }
+                _ => {}
+            }
+        }
+        // Dispatch sizes are derived from it
+        if workgroup_size.is_some_and(|size| size.contains(&0)) {
+            return Err(invalid("workgroup size has a zero dimension"));
+        }
+
+        Ok(Self {
+            stage: execution_model_to_stage(model),
+            bindings,
+            push_constants_size,
+            workgroup_size,
+        })
+    }
+
+    /// Highest set index used plus one.
+    pub fn set_count(&self) -> u32 {
+        self.bindings
+            .iter()
+            .map(|b| b.set.saturating_add(1))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Every binding of a given set.
+    pub fn set_bindings(&self, set: u32) -> impl Iterator<Item = &DescriptorBinding> {
+        self.bindings.iter().filter(move |b| b.set == set)
+    }
+
+    pub(crate) unsafe fn create_set_layouts(
+        &self,
+        device: &ash::Device,
+    ) -> Result<Vec<vk::DescriptorSetLayout>> {
+        let mut layouts = Vec::with_capacity(self.set_count() as _);
+
+        for set in 0..self.set_count() {
+            let bindings = self
+                .set_bindings(set)
+                .map(|b| {
+                    vk::DescriptorSetLayoutBinding::builder()
+                        .binding(b.binding)
+                        .descriptor_type(b.ty)
+                        .descriptor_count(b.count)
+                        .stage_flags(self.stage)
+                        .build()
+                })
+                .collect::<Vec<_>>();
+
+            match device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+                None,
+            ) {
+                Ok(layout) => layouts.push(layout),
+                Err(e) => {
+                    for layout in layouts {
+                        device.destroy_descriptor_set_layout(layout, None);
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Ok(layouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Literals of the test modules
+    const MODEL_GL_COMPUTE: u32 = 5;
+    const MAIN: [u32; 2] = [0x6e69_616d, 0];
+
+    fn inst(opcode: u32, operands: &[u32]) -> Vec<u32> {
+        let mut words = vec![((operands.len() as u32 + 1) << 16) | opcode];
+        words.extend_from_slice(operands);
+        words
+    }
+
+    fn module(instructions: &[Vec<u32>]) -> Vec<u32> {
+        let mut words = vec![SPIRV_MAGIC, 0x0001_0300, 0, 100, 0];
+        instructions
+            .iter()
+            .for_each(|inst| words.extend_from_slice(inst));
+        words
+    }
+
+    fn entry_point() -> Vec<u32> {
+        inst(OP_ENTRY_POINT, &[MODEL_GL_COMPUTE, 1, MAIN[0], MAIN[1]])
+    }
+
+    /// float, vec4, uint and a constant 4, shared by most modules.
+    fn scalar_types() -> Vec<Vec<u32>> {
+        vec![
+            inst(OP_TYPE_FLOAT, &[2, 32]),
+            inst(OP_TYPE_VECTOR, &[3, 2, 4]),
+            inst(OP_TYPE_INT, &[4, 32, 0]),
+            inst(OP_CONSTANT, &[4, 5, 4]),
+        ]
+    }
+
+    fn push_constant_of(ty: u32) -> Vec<Vec<u32>> {
+        vec![
+            inst(OP_TYPE_POINTER, &[90, STORAGE_PUSH_CONSTANT, ty]),
+            inst(OP_VARIABLE, &[90, 91, STORAGE_PUSH_CONSTANT]),
+        ]
+    }
+
+    fn reflect(instructions: Vec<Vec<u32>>) -> Result<ShaderReflection> {
+        ShaderReflection::from_spirv(&module(&instructions), "main")
+    }
+
+    fn assert_invalid(res: Result<ShaderReflection>) {
+        assert!(
+            matches!(res, Err(VulkanError::InvalidSpirv(_))),
+            "Expected invalid SPIR-V, got {:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn bindings_and_workgroup_size() {
+        let mut instructions = vec![
+            entry_point(),
+            inst(OP_EXECUTION_MODE, &[1, MODE_LOCAL_SIZE, 8, 4, 1]),
+        ];
+        instructions.extend(scalar_types());
+        instructions.extend(vec![
+            // Storage buffer at set 0 binding 1
+            inst(OP_TYPE_RUNTIME_ARRAY, &[10, 2]),
+            inst(OP_TYPE_STRUCT, &[11, 10]),
+            inst(OP_DECORATE, &[11, DECORATION_BLOCK]),
+            inst(OP_TYPE_POINTER, &[12, STORAGE_STORAGE_BUFFER, 11]),
+            inst(OP_VARIABLE, &[12, 13, STORAGE_STORAGE_BUFFER]),
+            inst(OP_DECORATE, &[13, DECORATION_DESCRIPTOR_SET, 0]),
+            inst(OP_DECORATE, &[13, DECORATION_BINDING, 1]),
+            // Uniform buffer at set 1 binding 0
+            inst(OP_TYPE_STRUCT, &[14, 3]),
+            inst(OP_DECORATE, &[14, DECORATION_BLOCK]),
+            inst(OP_TYPE_POINTER, &[15, STORAGE_UNIFORM, 14]),
+            inst(OP_VARIABLE, &[15, 16, STORAGE_UNIFORM]),
+            inst(OP_DECORATE, &[16, DECORATION_DESCRIPTOR_SET, 1]),
+            inst(OP_DECORATE, &[16, DECORATION_BINDING, 0]),
+            // Array of 3 storage buffers at set 0 binding 0
+            inst(OP_CONSTANT, &[4, 17, 3]),
+            inst(OP_TYPE_ARRAY, &[18, 11, 17]),
+            inst(OP_TYPE_POINTER, &[19, STORAGE_STORAGE_BUFFER, 18]),
+            inst(OP_VARIABLE, &[19, 20, STORAGE_STORAGE_BUFFER]),
+            inst(OP_DECORATE, &[20, DECORATION_DESCRIPTOR_SET, 0]),
+            inst(OP_DECORATE, &[20, DECORATION_BINDING, 0]),
+            // Old style storage buffer at set 1 binding 2
+            inst(OP_TYPE_STRUCT, &[21, 10]),
+            inst(OP_DECORATE, &[21, DECORATION_BUFFER_BLOCK]),
+            inst(OP_TYPE_POINTER, &[22, STORAGE_UNIFORM, 21]),
+            inst(OP_VARIABLE, &[22, 23, STORAGE_UNIFORM]),
+            inst(OP_DECORATE, &[23, DECORATION_DESCRIPTOR_SET, 1]),
+            inst(OP_DECORATE, &[23, DECORATION_BINDING, 2]),
+        ]);
+
+        let reflection = reflect(instructions).unwrap();
+        let binding = |set, binding, ty, count| DescriptorBinding {
+            set,
+            binding,
+            ty,
+            count,
+        };
+
+        assert_eq!(reflection.stage, vk::ShaderStageFlags::COMPUTE);
+        assert_eq!(reflection.workgroup_size, Some([8, 4, 1]));
+        assert_eq!(reflection.push_constants_size, 0);
+        assert_eq!(
+            reflection.bindings,
+            vec![
+                binding(0, 0, vk::DescriptorType::STORAGE_BUFFER, 3),
+                binding(0, 1, vk::DescriptorType::STORAGE_BUFFER, 1),
+                binding(1, 0, vk::DescriptorType::UNIFORM_BUFFER, 1),
+                binding(1, 2, vk::DescriptorType::STORAGE_BUFFER, 1),
+            ]
+        );
+        assert_eq!(reflection.set_count(), 2);
+    }
+
+    #[test]
+    fn workgroup_size_from_constants() {
+        let mut instructions = vec![entry_point()];
+        instructions.extend(scalar_types());
+        instructions.extend(vec![
+            inst(OP_CONSTANT, &[4, 6, 16]),
+            inst(OP_CONSTANT, &[4, 7, 1]),
+            inst(OP_EXECUTION_MODE_ID, &[1, MODE_LOCAL_SIZE_ID, 6, 5, 7]),
+        ]);
+
+        let reflection = reflect(instructions).unwrap();
+        assert_eq!(reflection.workgroup_size, Some([16, 4, 1]));
+    }
+
+    #[test]
+    fn zero_workgroup_size() {
+        let mut instructions = vec![
+            entry_point(),
+            inst(OP_EXECUTION_MODE, &[1, MODE_LOCAL_SIZE, 8, 0, 1]),
+        ];
+        instructions.extend(scalar_types());
+        assert_invalid(reflect(instructions));
+
+        let mut instructions = vec![entry_point()];
+        instructions.extend(scalar_types());
+        instructions.extend(vec![
+            inst(OP_CONSTANT, &[4, 6, 0]),
+            inst(OP_EXECUTION_MODE_ID, &[1, MODE_LOCAL_SIZE_ID, 6, 6, 6]),
+        ]);
+        assert_invalid(reflect(instructions));
+    }
+
+    #[test]
+    fn push_constants_with_offsets_and_strides() {
+        let mut instructions = vec![entry_point()];
+        instructions.extend(scalar_types());
+        instructions.extend(vec![
+            // float[4] with a stride of 16
+            inst(OP_TYPE_ARRAY, &[6, 2, 5]),
+            inst(OP_DECORATE, &[6, DECORATION_ARRAY_STRIDE, 16]),
+            // mat4
+            inst(OP_TYPE_MATRIX, &[7, 3, 4]),
+            // { float a; vec4 b; float c[4]; mat4 d; }
+            inst(OP_TYPE_STRUCT, &[8, 2, 3, 6, 7]),
+            inst(OP_DECORATE, &[8, DECORATION_BLOCK]),
+            inst(OP_MEMBER_DECORATE, &[8, 0, DECORATION_OFFSET, 0]),
+            inst(OP_MEMBER_DECORATE, &[8, 1, DECORATION_OFFSET, 16]),
+            inst(OP_MEMBER_DECORATE, &[8, 2, DECORATION_OFFSET, 32]),
+            inst(OP_MEMBER_DECORATE, &[8, 3, DECORATION_OFFSET, 96]),
+            inst(OP_MEMBER_DECORATE, &[8, 3, DECORATION_MATRIX_STRIDE, 32]),
+        ]);
+        instructions.extend(push_constant_of(8));
+
+        let reflection = reflect(instructions).unwrap();
+        // The matrix ends at 96 + 4 columns of 32 bytes
+        assert_eq!(reflection.push_constants_size, 224);
+        assert!(reflection.bindings.is_empty());
+        assert_eq!(reflection.set_count(), 0);
+    }
+
+    #[test]
+    fn push_constants_without_offsets() {
+        let mut instructions = vec![entry_point()];
+        instructions.extend(scalar_types());
+        instructions.extend(vec![inst(OP_TYPE_STRUCT, &[8, 2, 2, 3])]);
+        instructions.extend(push_constant_of(8));
+
+        assert_eq!(reflect(instructions).unwrap().push_constants_size, 24);
+    }
+
+    #[test]
+    fn missing_entry_point() {
+        let mut instructions = vec![entry_point()];
+        instructions.extend(scalar_types());
+
+        let res = ShaderReflection::from_spirv(&module(&instructions), "other");
+        assert_invalid(res);
+    }
+
+    #[test]
+    fn missing_header() {
+        assert_invalid(ShaderReflection::from_spirv(&[], "main"));
+        assert_invalid(ShaderReflection::from_spirv(&[SPIRV_MAGIC, 0, 0], "main"));
+
+        let mut words = module(&[entry_point()]);
+        words[0] = 0xdead_beef;
+        assert_invalid(ShaderReflection::from_spirv(&words, "main"));
+    }
+
+    #[test]
+    fn truncated_instruction() {
+        let mut words = module(&[entry_point(), inst(OP_TYPE_VECTOR, &[3, 2, 4])]);
+        words.truncate(words.len() - 1);
+        assert_invalid(ShaderReflection::from_spirv(&words, "main"));
+
+        // A word count of 0 would never advance
+        let mut words = module(&[entry_point()]);
+        words.push(OP_TYPE_BOOL);
+        assert_invalid(ShaderReflection::from_spirv(&words, "main"));
+    }
+
+    #[test]
+    fn missing_operand() {
+        assert_invalid(reflect(vec![entry_point(), inst(OP_TYPE_VECTOR, &[3, 2])]));
+    }
+
+    #[test]
+    fn unknown_array_length() {
+        let mut instructions = vec![entry_point()];
+        instructions.extend(scalar_types());
+        instructions.push(inst(OP_TYPE_ARRAY, &[6, 2, 42]));
+        assert_invalid(reflect(instructions));
+    }
+
+    #[test]
+    fn unknown_type() {
+        let mut instructions = vec![entry_point()];
+        instructions.extend(push_constant_of(42));
+        assert_invalid(reflect(instructions));
+    }
+
+    #[test]
+    fn size_overflow() {
+        let mut instructions = vec![entry_point()];
+        instructions.extend(scalar_types());
+        instructions.extend(vec![
+            inst(OP_CONSTANT, &[4, 6, u32::MAX]),
+            inst(OP_TYPE_ARRAY, &[7, 3, 6]),
+            inst(OP_TYPE_STRUCT, &[8, 7]),
+        ]);
+        instructions.extend(push_constant_of(8));
+        assert_invalid(reflect(instructions));
+
+        let mut instructions = vec![entry_point()];
+        instructions.extend(scalar_types());
+        instructions.extend(vec![
+            inst(OP_TYPE_STRUCT, &[8, 3]),
+            inst(OP_MEMBER_DECORATE, &[8, 0, DECORATION_OFFSET, u32::MAX - 4]),
+        ]);
+        instructions.extend(push_constant_of(8));
+        assert_invalid(reflect(instructions));
+    }
+
+    #[test]
+    fn cyclic_types() {
+        let mut instructions = vec![entry_point()];
+        instructions.extend(scalar_types());
+        instructions.extend(vec![
+            inst(OP_TYPE_STRUCT, &[8, 2, 9]),
+            inst(OP_TYPE_STRUCT, &[9, 8]),
+        ]);
+        instructions.extend(push_constant_of(8));
+        assert_invalid(reflect(instructions));
+    }
+}
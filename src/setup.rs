@@ -12,3 +12,6 @@ pub use device::*;
 
 mod queues;
 pub use queues::*;
+
+mod shutdown;
+pub use shutdown::*;
@@ -1,13 +1,15 @@
 use crate::{
     errors::{Result, VulkanError},
+    mem::{LiveAllocations, StagingPool},
     setup::{
         queues::{DeviceQueueIndices, DeviceQueues},
         DebugUtils, PhysicalDeviceInfo, VulkanInitializer,
     },
+    tasks::{FencePool, TimelineReactor},
     VulkanApp,
 };
-use ash::vk;
-use std::{ffi::CStr, mem::ManuallyDrop, os::raw::c_char, sync::Arc};
+use ash::{extensions::khr::Synchronization2, vk};
+use std::{ffi::CStr, mem::ManuallyDrop, os::raw::c_char, sync::Arc, time::Duration};
 
 type DeviceAdapter = (vk::PhysicalDevice, DeviceQueueIndices);
 
@@ -17,6 +19,10 @@ pub struct VulkanBuilder {
     pub(crate) debug_utils: ManuallyDrop<DebugUtils>,
     physical_device: Option<DeviceAdapter>,
     device_extensions: Vec<*const c_char>,
+    submission_batching: Option<Duration>,
+    synchronization2: bool,
+    #[cfg(all(target_os = "linux", feature = "sync-fd"))]
+    sync_fd: bool,
 }
 
 impl VulkanBuilder {
@@ -32,12 +38,21 @@ impl VulkanBuilder {
             physical_device: None,
             // Promoted to vulkan 1.1 so should be available
             device_extensions: vec![ash::vk::KhrDedicatedAllocationFn::name().as_ptr()],
+            submission_batching: None,
+            synchronization2: false,
+            #[cfg(all(target_os = "linux", feature = "sync-fd"))]
+            sync_fd: false,
         }
     }
 
     pub fn set_physical_device(mut self, device: PhysicalDeviceInfo) -> Self {
         let queues = DeviceQueueIndices::from_device(&device).unwrap();
         self.physical_device = Some((device.handle, queues));
+        self.synchronization2 = device.supports_synchronization2();
+        #[cfg(all(target_os = "linux", feature = "sync-fd"))]
+        {
+            self.sync_fd = device.supports_sync_fd();
+        }
         self
     }
 
@@ -45,6 +60,14 @@ impl VulkanBuilder {
         self.device_extensions.push(name.as_ptr());
         self
     }
+
+    /// Collect the submissions made during `window` and submit them with a single `vkQueueSubmit`.
+    ///
+    /// Each submission still gets its own future, submissions signaling a fence aren't batched.
+    pub fn with_submission_batching(mut self, window: Duration) -> Self {
+        self.submission_batching = Some(window);
+        self
+    }
 }
 
 impl VulkanBuilder {
@@ -56,16 +79,43 @@ impl VulkanBuilder {
                 .ok_or(VulkanError::NoPhysicalDevicePicked)?;
             let queue_create_info = physical.1.as_queue_create_info();
 
+            let mut extensions = self.device_extensions.clone();
+            let mut features_12 =
+                vk::PhysicalDeviceVulkan12Features::builder().timeline_semaphore(true);
+            let mut features_sync2 =
+                vk::PhysicalDeviceSynchronization2FeaturesKHR::builder().synchronization2(true);
+
+            let mut create_info = vk::DeviceCreateInfo::builder()
+                .queue_create_infos(&queue_create_info)
+                .push_next(&mut features_12);
+            if self.synchronization2 {
+                extensions.push(Synchronization2::name().as_ptr());
+                create_info = create_info.push_next(&mut features_sync2);
+            }
+            #[cfg(all(target_os = "linux", feature = "sync-fd"))]
+            if self.sync_fd {
+                extensions.push(ash::extensions::khr::ExternalFenceFd::name().as_ptr());
+            }
+
             unsafe {
                 self.instance.create_device(
                     physical.0,
-                    &vk::DeviceCreateInfo::builder()
-                        .enabled_extension_names(&self.device_extensions)
-                        .queue_create_infos(&queue_create_info),
+                    &create_info.enabled_extension_names(&extensions),
                     None,
                 )?
             }
         };
+        let synchronization2 = self
+            .synchronization2
+            .then(|| Synchronization2::new(&self.instance, &device));
+        #[cfg(all(target_os = "linux", feature = "sync-fd"))]
+        let external_fence_fd = self
+            .sync_fd
+            .then(|| ash::extensions::khr::ExternalFenceFd::new(&self.instance, &device));
+        #[cfg(all(target_os = "linux", feature = "sync-fd"))]
+        let exportable_fences = external_fence_fd.is_some();
+        #[cfg(not(all(target_os = "linux", feature = "sync-fd")))]
+        let exportable_fences = false;
 
         let vma = vk_mem::Allocator::new(&vk_mem::AllocatorCreateInfo {
             instance: self.instance.clone(),
@@ -75,15 +125,30 @@ impl VulkanBuilder {
             ..Default::default()
         })?;
 
-        let queues = DeviceQueues::new(&device, &self.physical_device.as_ref().unwrap().1)?;
+        let mut queues = DeviceQueues::new(&device, &self.physical_device.as_ref().unwrap().1)?;
+        let reactor = TimelineReactor::new(device.clone(), queues.timelines())?;
+        if let Some(window) = self.submission_batching {
+            queues.enable_batching(&device, &reactor, window);
+        }
+
+        let vma = Arc::new(vma);
+        let staging = StagingPool::new();
+        let fences = Arc::new(FencePool::new(device.clone(), exportable_fences));
 
         Ok(Arc::new(VulkanApp {
             _entry: self.entry,
             instance: self.instance,
             debug_utils: self.debug_utils,
             device,
-            vma: Arc::new(vma),
+            vma,
             queues,
+            reactor,
+            staging,
+            allocations: LiveAllocations::default(),
+            fences,
+            synchronization2,
+            #[cfg(all(target_os = "linux", feature = "sync-fd"))]
+            external_fence_fd,
         }))
     }
 }
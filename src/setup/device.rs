@@ -2,7 +2,11 @@ use crate::{
     errors::Result,
     setup::{queues::DeviceQueueIndices, VulkanBuilder, VULKAN_VERSION},
 };
-use ash::{vk, vk::QueueFamilyProperties2};
+use ash::{
+    extensions::khr::{ExternalFenceFd, Synchronization2},
+    vk,
+    vk::QueueFamilyProperties2,
+};
 use log::warn;
 use std::ffi::CStr;
 
@@ -12,6 +16,11 @@ pub struct PhysicalDeviceInfo {
     pub properties: vk::PhysicalDeviceProperties2,
     pub extensions: Vec<vk::ExtensionProperties>,
     pub features: vk::PhysicalDeviceFeatures2,
+    pub features_12: vk::PhysicalDeviceVulkan12Features,
+    /// Only queried when `VK_KHR_synchronization2` is available.
+    pub features_sync2: vk::PhysicalDeviceSynchronization2FeaturesKHR,
+    /// Fences can be exported as sync_fd with `VK_KHR_external_fence_fd`.
+    pub(crate) sync_fd: bool,
     pub queue_families: Vec<QueueFamilyProperties2>,
     pub memory_properties: vk::PhysicalDeviceMemoryProperties2,
 }
@@ -38,6 +47,16 @@ impl PhysicalDeviceInfo {
             .map(|heap| heap.size)
             .sum()
     }
+
+    /// Whether barriers can be recorded with `VK_KHR_synchronization2`.
+    pub fn supports_synchronization2(&self) -> bool {
+        self.features_sync2.synchronization2 == vk::TRUE
+    }
+
+    /// Whether fences can be exported as sync_fd, see the `sync-fd` feature.
+    pub fn supports_sync_fd(&self) -> bool {
+        self.sync_fd
+    }
 }
 
 impl VulkanBuilder {
@@ -56,9 +75,39 @@ impl VulkanBuilder {
                     .enumerate_device_extension_properties(d)
                     .expect("Failed to enumerate device extensions");
 
-                let mut features = vk::PhysicalDeviceFeatures2::default();
+                let has_extension = |name: &CStr| {
+                    extensions
+                        .iter()
+                        .any(|ext| CStr::from_ptr(ext.extension_name.as_ptr()) == name)
+                };
+                let has_sync2 = has_extension(Synchronization2::name());
+
+                let sync_fd = has_extension(ExternalFenceFd::name()) && {
+                    let mut properties = vk::ExternalFenceProperties::default();
+                    self.instance.get_physical_device_external_fence_properties(
+                        d,
+                        &vk::PhysicalDeviceExternalFenceInfo::builder()
+                            .handle_type(vk::ExternalFenceHandleTypeFlags::SYNC_FD),
+                        &mut properties,
+                    );
+                    properties
+                        .external_fence_features
+                        .contains(vk::ExternalFenceFeatureFlags::EXPORTABLE)
+                };
+
+                let mut features_12 = vk::PhysicalDeviceVulkan12Features::default();
+                let mut features_sync2 = vk::PhysicalDeviceSynchronization2FeaturesKHR::default();
+                let mut features =
+                    vk::PhysicalDeviceFeatures2::builder().push_next(&mut features_12);
+                if has_sync2 {
+                    features = features.push_next(&mut features_sync2);
+                }
+                let mut features = features.build();
                 self.instance
                     .get_physical_device_features2(d, &mut features);
+                // Don't keep pointers to the stack around
+                features.p_next = std::ptr::null_mut();
+                features_sync2.p_next = std::ptr::null_mut();
 
                 let mut queue_families = Vec::new();
                 queue_families.resize_with(
@@ -78,6 +127,9 @@ impl VulkanBuilder {
                     properties,
                     extensions,
                     features,
+                    features_12,
+                    features_sync2,
+                    sync_fd,
                     queue_families,
                     memory_properties,
                 }
@@ -108,6 +160,11 @@ impl VulkanBuilder {
         // Check required extensions
         // TODO: no extensions yet
 
+        // Check required features
+        if info.features_12.timeline_semaphore == vk::FALSE {
+            return false;
+        }
+
         // Check queues
         if let Err(_) = DeviceQueueIndices::from_device(info) {
             return false;
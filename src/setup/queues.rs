@@ -1,11 +1,33 @@
 use crate::{
     errors::{Result, VulkanError},
     setup::PhysicalDeviceInfo,
-    tasks::WaitForFenceFuture,
+    tasks::{
+        CommandPools, ReactorShared, Resource, SubmitBatcher, Timeline, TimelineFuture,
+        TimelineReactor,
+    },
 };
-use ash::vk;
+use ash::{prelude::VkResult, vk};
+use log::error;
 
 use parking_lot::Mutex;
+use std::{
+    slice::from_ref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// The kind of queue to submit work to.
+///
+/// Depending on the device, several kinds can end up being the same queue.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum QueueKind {
+    Graphics,
+    Compute,
+    Transfer,
+}
 
 /// Represents the queue indices to use for graphics, compute and transfer.
 ///
@@ -120,16 +142,100 @@ impl DeviceQueueIndices {
     }
 }
 
+/// Flush a batch right away once it holds that many command buffers.
+const BATCH_MAX_COMMAND_BUFFERS: usize = 64;
+
 pub(crate) struct QueueWithPool {
-    pub(crate) queue: Mutex<vk::Queue>,
-    pub(crate) pool: Mutex<vk::CommandPool>,
+    pub(crate) family: u32,
+    pub(crate) queue: Mutex<QueueState>,
+    pub(crate) pools: CommandPools,
+    pub(crate) timeline: Arc<Timeline>,
+}
+
+pub(crate) struct QueueState {
+    handle: vk::Queue,
+    /// Submissions collected to be submitted together, when batching is enabled.
+    batch: Option<PendingBatch>,
+}
+
+struct PendingBatch {
+    /// Timeline value signaled by the whole batch, already reserved.
+    value: u64,
+    command_buffers: Vec<vk::CommandBuffer>,
+    deadline: Instant,
+}
+
+impl QueueState {
+    #[inline]
+    pub(crate) fn batch_deadline(&self) -> Option<Instant> {
+        self.batch.as_ref().map(|batch| batch.deadline)
+    }
+}
+
+impl QueueWithPool {
+    /// Submit the pending batch, if any.
+    ///
+    /// On failure its value would never be signaled, so the reactor is failed.
+    pub(crate) unsafe fn flush(
+        &self,
+        device: &ash::Device,
+        reactor: &ReactorShared,
+        state: &mut QueueState,
+    ) -> Result<()> {
+        if let Some(batch) = state.batch.take() {
+            let res = submit_signaling(
+                device,
+                state.handle,
+                &batch.command_buffers,
+                &[],
+                &self.timeline,
+                batch.value,
+                vk::Fence::null(),
+            );
+            if let Err(e) = res {
+                reactor.fail(e);
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Submit after every timeline semaphore of `waits` reached its value, then signal `value`.
+unsafe fn submit_signaling(
+    device: &ash::Device,
+    queue: vk::Queue,
+    command_buffers: &[vk::CommandBuffer],
+    waits: &[(vk::Semaphore, u64)],
+    timeline: &Timeline,
+    value: u64,
+    fence: vk::Fence,
+) -> VkResult<()> {
+    let (wait_semaphores, wait_values): (Vec<_>, Vec<_>) = waits.iter().copied().unzip();
+    // Nothing of the submission can start before
+    let wait_stages = vec![vk::PipelineStageFlags::ALL_COMMANDS; waits.len()];
+
+    let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::builder()
+        .wait_semaphore_values(&wait_values)
+        .signal_semaphore_values(from_ref(&value));
+    let submit_info = vk::SubmitInfo::builder()
+        .command_buffers(command_buffers)
+        .wait_semaphores(&wait_semaphores)
+        .wait_dst_stage_mask(&wait_stages)
+        .signal_semaphores(from_ref(&timeline.semaphore))
+        .push_next(&mut timeline_info);
+
+    device.queue_submit(queue, from_ref(&submit_info), fence)
 }
 
 pub(crate) struct DeviceQueues {
-    pub(crate) queues: [Option<QueueWithPool>; 3],
+    pub(crate) queues: [Option<Arc<QueueWithPool>>; 3],
     pub(crate) graphics_index: usize,
     pub(crate) compute_index: usize,
     pub(crate) transfer_index: usize,
+    /// Set once the app is shutting down, submissions are refused from then on.
+    closed: AtomicBool,
+    batcher: Option<SubmitBatcher>,
 }
 
 impl DeviceQueues {
@@ -161,46 +267,208 @@ impl DeviceQueues {
                 graphics_index: graphics,
                 compute_index: compute,
                 transfer_index: transfer,
+                closed: AtomicBool::new(false),
+                batcher: None,
             })
         }
     }
 
-    unsafe fn create_queue_and_pool(device: &ash::Device, index: u32) -> Result<QueueWithPool> {
+    unsafe fn create_queue_and_pool(
+        device: &ash::Device,
+        index: u32,
+    ) -> Result<Arc<QueueWithPool>> {
         let queue = device.get_device_queue(index, 0);
-        let pool = device.create_command_pool(
-            &vk::CommandPoolCreateInfo::builder()
-                .queue_family_index(index)
-                .flags(vk::CommandPoolCreateFlags::TRANSIENT),
-            None,
-        )?;
-
-        Ok(QueueWithPool {
-            queue: Mutex::new(queue),
-            pool: Mutex::new(pool),
-        })
+
+        Ok(Arc::new(QueueWithPool {
+            family: index,
+            queue: Mutex::new(QueueState {
+                handle: queue,
+                batch: None,
+            }),
+            pools: CommandPools::new(index),
+            timeline: Arc::new(Timeline::new(device)?),
+        }))
     }
 
-    pub(crate) fn submit_to_transfer<'a>(
+    /// Collect submissions for `window` and submit them together, see [`SubmitBatcher`].
+    pub(crate) fn enable_batching(
+        &mut self,
+        device: &ash::Device,
+        reactor: &TimelineReactor,
+        window: Duration,
+    ) {
+        self.batcher = Some(SubmitBatcher::new(
+            device.clone(),
+            Arc::clone(reactor.shared()),
+            self.queues.iter().flatten().cloned().collect(),
+            window,
+        ));
+    }
+
+    pub(crate) fn submit_to_transfer(
         &self,
-        device: &'a ash::Device,
-        submit_info: &[vk::SubmitInfo],
-    ) -> Result<WaitForFenceFuture<'a>> {
-        unsafe { self.submit_to_queue(device, &self.transfer().queue, submit_info) }
+        device: &ash::Device,
+        reactor: &TimelineReactor,
+        command_buffers: &[vk::CommandBuffer],
+    ) -> Result<TimelineFuture> {
+        unsafe {
+            self.submit_to_queue(
+                device,
+                reactor,
+                self.transfer(),
+                command_buffers,
+                &[],
+                Vec::new(),
+                vk::Fence::null(),
+            )
+        }
     }
 
-    pub(crate) unsafe fn submit_to_queue<'a>(
+    /// Submit and signal the next value of the timeline of the queue.
+    ///
+    /// The submission only starts once the timeline semaphores of `waits` reached their value.
+    /// `resources` are kept alive until the submission completes, `fence` can be null.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) unsafe fn submit_to_queue(
         &self,
-        device: &'a ash::Device,
-        queue: &Mutex<vk::Queue>,
-        submit_info: &[vk::SubmitInfo],
-    ) -> Result<WaitForFenceFuture<'a>> {
-        let fence = device.create_fence(&vk::FenceCreateInfo::builder(), None)?;
-        {
-            let queue = queue.lock();
-            device.queue_submit(*queue, submit_info, fence)?;
+        device: &ash::Device,
+        reactor: &TimelineReactor,
+        queue: &QueueWithPool,
+        command_buffers: &[vk::CommandBuffer],
+        waits: &[(vk::Semaphore, u64)],
+        resources: Vec<Resource>,
+        fence: vk::Fence,
+    ) -> Result<TimelineFuture> {
+        let value = {
+            let mut state = queue.queue.lock();
+            if self.closed.load(Ordering::Acquire) {
+                return Err(VulkanError::AppShutDown);
+            }
+            // Fail fast instead of submitting to a lost device
+            if let Some(e) = reactor.shared().error() {
+                return Err(e.into());
+            }
+
+            // Submissions with a fence or waits need their own vkQueueSubmit
+            let batcher = self
+                .batcher
+                .as_ref()
+                .filter(|_| fence == vk::Fence::null() && waits.is_empty());
+
+            // Values must be strictly increasing in submission order, hence the lock
+            let value = match (batcher, &mut state.batch) {
+                (Some(_), Some(batch)) => {
+                    batch.command_buffers.extend_from_slice(command_buffers);
+                    batch.value
+                }
+                (Some(batcher), None) => {
+                    let value = queue.timeline.last_submitted.load(Ordering::Acquire) + 1;
+                    state.batch = Some(PendingBatch {
+                        value,
+                        command_buffers: command_buffers.to_vec(),
+                        deadline: Instant::now() + batcher.window(),
+                    });
+                    batcher.notify_new_batch();
+                    value
+                }
+                (None, _) => {
+                    // The pending batch has a smaller value, it must be signaled first
+                    queue.flush(device, reactor.shared(), &mut state)?;
+
+                    let value = queue.timeline.last_submitted.load(Ordering::Acquire) + 1;
+                    let res = submit_signaling(
+                        device,
+                        state.handle,
+                        command_buffers,
+                        waits,
+                        &queue.timeline,
+                        value,
+                        fence,
+                    );
+                    if let Err(e) = res {
+                        if e == vk::Result::ERROR_DEVICE_LOST {
+                            reactor.shared().fail(e);
+                        }
+                        return Err(e.into());
+                    }
+                    value
+                }
+            };
+
+            queue
+                .timeline
+                .last_submitted
+                .store(value, Ordering::Release);
+            reactor
+                .shared()
+                .retain_until(&queue.timeline, value, resources);
+
+            if state.batch.as_ref().map_or(false, |batch| {
+                batch.command_buffers.len() >= BATCH_MAX_COMMAND_BUFFERS
+            }) {
+                queue.flush(device, reactor.shared(), &mut state)?;
+            }
+            value
+        };
+
+        Ok(TimelineFuture::new(
+            reactor.shared(),
+            &queue.timeline,
+            value,
+        ))
+    }
+
+    /// Every distinct timeline, one per queue actually created.
+    pub(crate) fn timelines(&self) -> Vec<Arc<Timeline>> {
+        self.queues
+            .iter()
+            .flatten()
+            .map(|q| Arc::clone(&q.timeline))
+            .collect()
+    }
+
+    /// Submit every pending batch right away.
+    pub(crate) fn flush(&self, device: &ash::Device, reactor: &TimelineReactor) -> Result<()> {
+        for queue in self.queues.iter().flatten() {
+            let mut state = queue.queue.lock();
+            unsafe { queue.flush(device, reactor.shared(), &mut state)? };
         }
+        Ok(())
+    }
+
+    /// Refuse any new submission and flush pending batches.
+    /// Once this returns no submission is in progress anymore.
+    pub(crate) fn close(&self, device: &ash::Device, reactor: &TimelineReactor) {
+        self.closed.store(true, Ordering::Release);
+        // Also waits for submissions that got in before the flag was set
+        if let Err(e) = self.flush(device, reactor) {
+            error!("Failed to flush submissions when closing the queues: {}", e);
+        }
+        if let Some(batcher) = &self.batcher {
+            batcher.stop();
+        }
+    }
 
-        Ok(WaitForFenceFuture { device, fence })
+    /// The device must be idle.
+    pub(crate) unsafe fn destroy(&self, device: &ash::Device) {
+        for queue in self.queues.iter().flatten() {
+            queue.timeline.release_all();
+            device.destroy_semaphore(queue.timeline.semaphore, None);
+            queue.pools.destroy(device);
+        }
+    }
+
+    /// Every queue family used by the app, without duplicates.
+    pub(crate) fn families(&self) -> Vec<u32> {
+        self.queues.iter().flatten().map(|q| q.family).collect()
+    }
+
+    pub(crate) fn get(&self, kind: QueueKind) -> &QueueWithPool {
+        match kind {
+            QueueKind::Graphics => self.graphics(),
+            QueueKind::Compute => self.compute(),
+            QueueKind::Transfer => self.transfer(),
+        }
     }
 
     pub(crate) fn graphics(&self) -> &QueueWithPool {
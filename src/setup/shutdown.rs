@@ -0,0 +1,111 @@
+use crate::{tasks::TimelineFuture, VulkanApp};
+use ash::vk;
+use futures::channel::oneshot;
+use log::{error, warn};
+use std::{sync::Arc, thread};
+
+/// What was still alive when the app was shut down.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Other references to the app, e.g. pipelines and descriptor sets.
+    /// The device is only destroyed once they are all dropped.
+    pub app_references: usize,
+    /// Buffers and images that are still alive, by kind and handle.
+    /// The device is leaked if they are still there when the app is dropped.
+    pub allocations: Vec<LeakedAllocation>,
+}
+
+impl ShutdownReport {
+    /// Whether everything has been destroyed.
+    pub fn is_clean(&self) -> bool {
+        self.app_references == 0 && self.allocations.is_empty()
+    }
+}
+
+/// Kind of Vulkan object of a [`LeakedAllocation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AllocationKind {
+    Buffer,
+    Image,
+}
+
+/// A buffer or image still alive at shutdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeakedAllocation {
+    pub kind: AllocationKind,
+    /// The raw Vulkan handle, as in validation layer messages.
+    pub handle: u64,
+    /// Size of its memory in bytes.
+    pub size: vk::DeviceSize,
+}
+
+impl VulkanApp {
+    /// Stop accepting submissions, wait for everything in flight and destroy the device.
+    ///
+    /// The device is only destroyed if nothing else references the app anymore,
+    /// the returned report tells what is still alive otherwise.
+    pub async fn shutdown(self: Arc<Self>) -> ShutdownReport {
+        self.queues.close(&self.device, &self.reactor);
+
+        for queue in self.queues.queues.iter().flatten() {
+            let last = queue
+                .timeline
+                .last_submitted
+                .load(std::sync::atomic::Ordering::Acquire);
+            let future = TimelineFuture::new(self.reactor.shared(), &queue.timeline, last);
+            if let Err(e) = future.await {
+                error!("Failed to wait for submissions before shutting down: {}", e);
+            }
+        }
+
+        // Waiting for the device and joining the reactor thread would block the executor
+        let (sender, receiver) = oneshot::channel();
+        let teardown = thread::Builder::new()
+            .name("vk-async-shutdown".into())
+            .spawn(move || {
+                let _ = sender.send(self.teardown());
+            });
+
+        match teardown {
+            Ok(_) => receiver.await.unwrap_or_else(|_| {
+                error!("The shutdown thread panicked");
+                ShutdownReport::default()
+            }),
+            Err(e) => {
+                error!("Failed to spawn the shutdown thread: {}", e);
+                ShutdownReport::default()
+            }
+        }
+    }
+
+    /// Blocking part of [`shutdown`](Self::shutdown), once every submission is complete.
+    fn teardown(self: Arc<Self>) -> ShutdownReport {
+        unsafe {
+            if let Err(e) = self.device.device_wait_idle() {
+                error!("Failed to wait for the device to be idle: {}", e);
+            }
+        }
+        self.staging.clear();
+
+        let report = ShutdownReport {
+            app_references: Arc::strong_count(&self) - 1,
+            allocations: self.allocations.snapshot(),
+        };
+        for leaked in &report.allocations {
+            warn!(
+                "{:?} 0x{:x} of {} bytes is still alive at shutdown",
+                leaked.kind, leaked.handle, leaked.size
+            );
+        }
+
+        match Arc::try_unwrap(self) {
+            Ok(app) => drop(app),
+            Err(_) => warn!(
+                "{} references to the app are still alive, the device will be destroyed with the last one",
+                report.app_references
+            ),
+        }
+
+        report
+    }
+}
@@ -1,50 +1,214 @@
 use crate::{
     errors::{Result, VulkanError},
-    mem::RawAllocation,
+    setup::QueueKind,
     VulkanApp,
 };
-use ash::vk;
-
 use std::{
+    any::Any,
     future::Future,
     pin::Pin,
-    slice::from_ref,
+    sync::Arc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 mod alloc;
+pub(crate) use alloc::{CommandBufferLease, CommandPools, RecordingGuard};
+
 mod commands;
+pub use commands::{CommandRecorder, RecordedCommands};
+
+mod barriers;
+pub use barriers::{Access, PipelineBarrier};
+
+mod hazards;
+pub(crate) use hazards::{BufferAccess, HazardTracker, ImageAccess};
+
+mod ownership;
+pub(crate) use ownership::{push_owned, Owned, QueueOwner, Transferred};
+
+mod batch;
+pub(crate) use batch::SubmitBatcher;
+
+mod fences;
+pub(crate) use fences::FencePool;
+pub use fences::{FenceFuture, FencePoolStats};
+
+#[cfg(all(target_os = "linux", feature = "sync-fd"))]
+mod sync_fd;
+#[cfg(all(target_os = "linux", feature = "sync-fd-tokio"))]
+pub use sync_fd::SyncFdFuture;
+
+mod reactor;
+use reactor::ReactorFailure;
+pub(crate) use reactor::{ReactorShared, Timeline, TimelineReactor};
+
+/// Anything a submission must keep alive until the GPU is done with it.
+pub(crate) type Resource = Arc<dyn Any + Send + Sync>;
+
+/// Resolves once the timeline semaphore of a queue reaches a given value.
+///
+/// Every submission signals the next value of the timeline of its queue,
+/// so this is how the completion of GPU work is awaited.
+///
+/// Dropping it doesn't cancel anything, like a `JoinHandle` the submission keeps running
+/// and what it uses is freed in the background once it completes.
+pub struct TimelineFuture {
+    reactor: Arc<ReactorShared>,
+    timeline: Arc<Timeline>,
+    value: u64,
+    deadline: Option<Instant>,
+    /// Registration of the deadline with the reactor, removed once this resolves or is dropped.
+    deadline_id: Option<u64>,
+}
+
+impl TimelineFuture {
+    pub(crate) fn new(reactor: &Arc<ReactorShared>, timeline: &Arc<Timeline>, value: u64) -> Self {
+        Self {
+            reactor: Arc::clone(reactor),
+            timeline: Arc::clone(timeline),
+            value,
+            deadline: None,
+            deadline_id: None,
+        }
+    }
+
+    /// Resolve with [`VulkanError::Timeout`] if the value isn't reached within `timeout` from now.
+    ///
+    /// The submission itself keeps running, e.g. to wait again with a longer timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(Instant::now() + timeout);
+        self
+    }
+
+    /// The timeline value this future is waiting for.
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    #[inline]
+    pub(crate) fn timeline(&self) -> &Arc<Timeline> {
+        &self.timeline
+    }
+
+    /// Whether the value has already been reached, without registering anything.
+    pub fn is_complete(&self) -> bool {
+        self.timeline.is_reached(self.value)
+    }
+
+    /// Let the submission complete on its own, for fire-and-forget work.
+    ///
+    /// Same as dropping the future, but explicit.
+    pub fn detach(self) {}
+
+    /// Keep `resource` alive until the value is reached.
+    pub(crate) fn retain(&self, resource: Resource) {
+        self.reactor
+            .retain_until(&self.timeline, self.value, vec![resource]);
+    }
+}
 
-const WAIT_FOR_FENCE_SPIN_INTERVALS_NS: u64 = 200;
+impl Clone for TimelineFuture {
+    fn clone(&self) -> Self {
+        Self {
+            reactor: Arc::clone(&self.reactor),
+            timeline: Arc::clone(&self.timeline),
+            value: self.value,
+            deadline: self.deadline,
+            // Each clone registers its own waker
+            deadline_id: None,
+        }
+    }
+}
 
-pub(crate) struct WaitForFenceFuture<'a> {
-    pub(crate) device: &'a ash::Device,
-    pub(crate) fence: vk::Fence,
+impl Drop for TimelineFuture {
+    fn drop(&mut self) {
+        if let Some(id) = self.deadline_id.take() {
+            self.reactor.cancel_deadline(id);
+        }
+    }
 }
 
-impl Future for WaitForFenceFuture<'_> {
+impl Future for TimelineFuture {
     type Output = Result<()>;
 
     fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
-        unsafe {
-            // Wait for a little time while, then let other things run and spin again
-            let res = self.device.wait_for_fences(
-                from_ref(&self.fence),
-                true,
-                WAIT_FOR_FENCE_SPIN_INTERVALS_NS,
-            );
+        let this = self.get_mut();
+        let res = this.poll_value(ctx);
+        if res.is_ready() {
+            if let Some(id) = this.deadline_id.take() {
+                this.reactor.cancel_deadline(id);
+            }
+        }
+        res
+    }
+}
 
-            match res {
-                Ok(_) => {
-                    self.device.destroy_fence(self.fence, None);
-                    Poll::Ready(Ok(()))
-                }
-                Err(vk::Result::TIMEOUT) => {
-                    ctx.waker().wake_by_ref();
-                    Poll::Pending
-                }
-                Err(e) => Poll::Ready(Err(VulkanError::VkError(e))),
+impl TimelineFuture {
+    fn poll_value(&mut self, ctx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.timeline.is_reached(self.value) {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Poll::Ready(Err(VulkanError::Timeout));
             }
+            self.deadline_id = Some(
+                self.reactor
+                    .wake_at(self.deadline_id, deadline, ctx.waker()),
+            );
         }
+
+        match self
+            .reactor
+            .wait_for(&self.timeline, self.value, ctx.waker())
+        {
+            // It may have been reached in the meantime and the waker would never be called
+            Ok(_) if self.timeline.is_reached(self.value) => Poll::Ready(Ok(())),
+            Ok(_) => Poll::Pending,
+            Err(ReactorFailure::Stopped) => Poll::Ready(Err(VulkanError::AppShutDown)),
+            Err(ReactorFailure::Error(e)) => Poll::Ready(Err(e.into())),
+        }
+    }
+}
+
+impl VulkanApp {
+    /// Last timeline value signaled by a submission to this queue.
+    pub fn last_submitted_value(&self, queue: QueueKind) -> u64 {
+        self.queues
+            .get(queue)
+            .timeline
+            .last_submitted
+            .load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Whether the device has been lost, or its timelines can't be waited on anymore.
+    ///
+    /// Every submission fails right away from then on.
+    pub fn is_poisoned(&self) -> bool {
+        self.reactor.shared().error().is_some()
+    }
+
+    /// Counters of the pool of fences used by [`RecordedCommands::submit_with_fence`].
+    pub fn fence_pool_stats(&self) -> FencePoolStats {
+        self.fences.stats()
+    }
+
+    /// Submit the batches collected so far without waiting for the end of their window.
+    ///
+    /// Only useful if [`VulkanBuilder::with_submission_batching`](crate::setup::VulkanBuilder::with_submission_batching) has been used.
+    pub fn flush_submissions(&self) -> Result<()> {
+        self.queues.flush(&self.device, &self.reactor)
+    }
+
+    /// Wait for the timeline of a queue to reach `value`.
+    ///
+    /// Queues can share the same timeline if they come from the same family.
+    pub fn wait_for_value(&self, queue: QueueKind, value: u64) -> TimelineFuture {
+        TimelineFuture::new(
+            self.reactor.shared(),
+            &self.queues.get(queue).timeline,
+            value,
+        )
     }
 }
@@ -1,28 +1,285 @@
-use crate::{errors::Result, VulkanApp};
+use crate::errors::Result;
 use ash::vk;
-use parking_lot::MutexGuard;
+use parking_lot::{Mutex, ReentrantMutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    thread::{self, ThreadId},
+};
 
-impl VulkanApp {
-    pub(crate) unsafe fn alloc_transient_transfer_cmd_buffer(
-        &self,
-    ) -> Result<(MutexGuard<vk::CommandPool>, vk::CommandBuffer)> {
-        let pool = self.queues.transfer().pool.lock();
-        let cmd = self.allocate_primary_buffers_from_pool(*pool, 1)?[0];
+thread_local! {
+    /// Dropped when the thread exits, which lets other threads take over its pools.
+    static THREAD_ALIVE: Arc<()> = Arc::new(());
+}
+
+/// Command pools of a queue family, one per recording thread so that they don't contend.
+///
+/// The pool of a thread that exited goes to the next thread needing one, so there are never
+/// more pools than threads recording at the same time. They are only destroyed with the app.
+pub(crate) struct CommandPools {
+    family: u32,
+    pools: Mutex<HashMap<ThreadId, OwnedPool>>,
+}
+
+/// A pool and whether the thread owning it is still alive.
+struct OwnedPool {
+    owner: Weak<()>,
+    pool: Arc<ThreadCommandPool>,
+}
+
+impl CommandPools {
+    pub(crate) fn new(family: u32) -> Self {
+        Self {
+            family,
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The pool of the calling thread, created on first use.
+    pub(crate) fn current(&self, device: &ash::Device) -> Result<Arc<ThreadCommandPool>> {
+        let mut pools = self.pools.lock();
+        let thread = thread::current().id();
+        if let Some(owned) = pools.get(&thread) {
+            return Ok(Arc::clone(&owned.pool));
+        }
+        if let Some(pool) = take_over_idle(&mut pools, thread) {
+            return Ok(pool);
+        }
 
-        Ok((pool, cmd))
+        let pool = unsafe {
+            device.create_command_pool(
+                &vk::CommandPoolCreateInfo::builder()
+                    .queue_family_index(self.family)
+                    .flags(
+                        vk::CommandPoolCreateFlags::TRANSIENT
+                            | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+                    ),
+                None,
+            )?
+        };
+        let pool = Arc::new(ThreadCommandPool {
+            pool: ReentrantMutex::new(pool),
+            free: Mutex::new(Vec::new()),
+        });
+        pools.insert(
+            thread,
+            OwnedPool {
+                owner: current_thread_alive(),
+                pool: Arc::clone(&pool),
+            },
+        );
+        Ok(pool)
     }
 
-    #[inline]
-    pub(crate) unsafe fn allocate_primary_buffers_from_pool(
-        &self,
+    /// Destroy every pool, which also frees their command buffers. The device must be idle.
+    pub(crate) unsafe fn destroy(&self, device: &ash::Device) {
+        for (_, owned) in self.pools.lock().drain() {
+            device.destroy_command_pool(*owned.pool.pool.lock(), None);
+        }
+    }
+}
+
+/// Give the pool of a thread that exited to `thread`.
+///
+/// Command buffers it executed may still be in flight, they only go back to its free list later.
+fn take_over_idle(
+    pools: &mut HashMap<ThreadId, OwnedPool>,
+    thread: ThreadId,
+) -> Option<Arc<ThreadCommandPool>> {
+    let idle = *pools
+        .iter()
+        .find(|(_, owned)| owned.owner.strong_count() == 0)?
+        .0;
+    let mut owned = pools.remove(&idle)?;
+    owned.owner = current_thread_alive();
+    let pool = Arc::clone(&owned.pool);
+    pools.insert(thread, owned);
+    Some(pool)
+}
+
+/// Alive as long as the calling thread, already dead if it is exiting.
+fn current_thread_alive() -> Weak<()> {
+    THREAD_ALIVE.try_with(Arc::downgrade).unwrap_or_default()
+}
+
+pub(crate) struct ThreadCommandPool {
+    /// Locked while allocating and recording.
+    ///
+    /// Only its thread records with it, re-entrant so that recording while already recording
+    /// doesn't deadlock. That is not concurrent use, Vulkan only forbids the latter.
+    pub(crate) pool: ReentrantMutex<vk::CommandPool>,
+    /// Executed command buffers, ready to be recorded again.
+    free: Mutex<Vec<vk::CommandBuffer>>,
+}
+
+impl ThreadCommandPool {
+    /// Reuse an executed command buffer or allocate a new one, `pool` is the locked pool.
+    ///
+    /// It is implicitly reset when beginning it.
+    pub(crate) unsafe fn acquire(
+        self: &Arc<Self>,
+        device: &ash::Device,
         pool: vk::CommandPool,
-        count: u32,
-    ) -> Result<Vec<vk::CommandBuffer>> {
-        Ok(self.device.allocate_command_buffers(
-            &vk::CommandBufferAllocateInfo::builder()
-                .command_pool(pool)
-                .command_buffer_count(count)
-                .level(vk::CommandBufferLevel::PRIMARY),
-        )?)
+    ) -> Result<CommandBufferLease> {
+        let recycled = self.free.lock().pop();
+        let cmd = match recycled {
+            Some(cmd) => cmd,
+            None => allocate_primary_buffers_from_pool(device, pool, 1)?[0],
+        };
+
+        Ok(CommandBufferLease {
+            pool: Arc::clone(self),
+            cmd,
+        })
+    }
+}
+
+/// A command buffer borrowed from a pool, given back to it when dropped.
+///
+/// Submissions retain it so that it is only recycled once executed.
+pub(crate) struct CommandBufferLease {
+    pool: Arc<ThreadCommandPool>,
+    pub(crate) cmd: vk::CommandBuffer,
+}
+
+impl Drop for CommandBufferLease {
+    fn drop(&mut self) {
+        self.pool.free.lock().push(self.cmd);
+    }
+}
+
+/// A lease whose command buffer is being recorded.
+///
+/// If dropped before [`finish`](Self::finish), because recording failed or panicked, `reset` is
+/// called on the command buffer before it goes back to the pool: one still in the recording state
+/// can't be begun again.
+pub(crate) struct RecordingGuard<F: FnOnce(vk::CommandBuffer)> {
+    lease: Option<CommandBufferLease>,
+    reset: Option<F>,
+}
+
+impl<F: FnOnce(vk::CommandBuffer)> RecordingGuard<F> {
+    pub(crate) fn new(lease: CommandBufferLease, reset: F) -> Self {
+        Self {
+            lease: Some(lease),
+            reset: Some(reset),
+        }
+    }
+
+    pub(crate) fn cmd(&self) -> vk::CommandBuffer {
+        self.lease.as_ref().unwrap().cmd
+    }
+
+    /// Recording ended, the command buffer doesn't need to be reset.
+    pub(crate) fn finish(mut self) -> CommandBufferLease {
+        self.lease.take().unwrap()
+    }
+}
+
+impl<F: FnOnce(vk::CommandBuffer)> Drop for RecordingGuard<F> {
+    fn drop(&mut self) {
+        if let (Some(lease), Some(reset)) = (self.lease.take(), self.reset.take()) {
+            reset(lease.cmd);
+        }
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn allocate_primary_buffers_from_pool(
+    device: &ash::Device,
+    pool: vk::CommandPool,
+    count: u32,
+) -> Result<Vec<vk::CommandBuffer>> {
+    Ok(device.allocate_command_buffers(
+        &vk::CommandBufferAllocateInfo::builder()
+            .command_pool(pool)
+            .command_buffer_count(count)
+            .level(vk::CommandBufferLevel::PRIMARY),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    fn test_pool() -> Arc<ThreadCommandPool> {
+        Arc::new(ThreadCommandPool {
+            pool: ReentrantMutex::new(vk::CommandPool::null()),
+            free: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn lease(pool: &Arc<ThreadCommandPool>, raw: u64) -> CommandBufferLease {
+        CommandBufferLease {
+            pool: Arc::clone(pool),
+            cmd: vk::CommandBuffer::from_raw(raw),
+        }
+    }
+
+    fn owned(pool: &Arc<ThreadCommandPool>) -> OwnedPool {
+        OwnedPool {
+            owner: current_thread_alive(),
+            pool: Arc::clone(pool),
+        }
+    }
+
+    #[test]
+    fn pools_of_exited_threads_are_taken_over() {
+        let pool = test_pool();
+        let mut pools = HashMap::new();
+
+        let (exited, owner) = thread::spawn(|| (thread::current().id(), owned(&test_pool())))
+            .join()
+            .unwrap();
+        let exited_pool = Arc::clone(&owner.pool);
+        pools.insert(exited, owner);
+        // Alive, not taken over
+        pools.insert(thread::current().id(), owned(&pool));
+
+        let thread = thread::spawn(|| thread::current().id()).join().unwrap();
+        let taken = take_over_idle(&mut pools, thread).unwrap();
+        assert!(Arc::ptr_eq(&taken, &exited_pool));
+        assert_eq!(pools.len(), 2);
+        assert!(!pools.contains_key(&exited));
+        assert!(Arc::ptr_eq(&pools[&thread::current().id()].pool, &pool));
+
+        // Owned by the calling thread now, which is alive
+        assert!(take_over_idle(&mut pools, exited).is_none());
+    }
+
+    #[test]
+    fn panic_while_recording_resets_before_recycling() {
+        let pool = test_pool();
+        // Command buffers reset, with how many buffers were free at that time
+        let resets = Mutex::new(Vec::new());
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            let recording = RecordingGuard::new(lease(&pool, 1), |cmd| {
+                resets.lock().push((cmd, pool.free.lock().len()));
+            });
+            let _cmd = recording.cmd();
+            panic!("Failed assertion in a recorder method");
+        }));
+
+        assert!(res.is_err());
+        assert_eq!(*resets.lock(), [(vk::CommandBuffer::from_raw(1), 0)]);
+        assert_eq!(*pool.free.lock(), [vk::CommandBuffer::from_raw(1)]);
+    }
+
+    #[test]
+    fn finished_recording_isnt_reset() {
+        let pool = test_pool();
+        let resets = Mutex::new(Vec::new());
+
+        let recording = RecordingGuard::new(lease(&pool, 1), |cmd| resets.lock().push(cmd));
+        let lease = recording.finish();
+        assert!(resets.lock().is_empty());
+        assert!(pool.free.lock().is_empty());
+
+        drop(lease);
+        assert!(resets.lock().is_empty());
+        assert_eq!(*pool.free.lock(), [vk::CommandBuffer::from_raw(1)]);
     }
 }
@@ -0,0 +1,324 @@
+use crate::{
+    mem::{Buffer, GpuImageHandle},
+    tasks::Resource,
+};
+use ash::vk;
+use std::ops::{BitOr, Range};
+
+/// Pipeline stages and the memory accesses they make, one side of a barrier.
+///
+/// Expressed with the `VK_KHR_synchronization2` flags, converted to the core ones when it's missing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub stage: vk::PipelineStageFlags2KHR,
+    pub access: vk::AccessFlags2KHR,
+}
+
+impl Access {
+    pub const NONE: Self = Self::new(vk::PipelineStageFlags2KHR::NONE, vk::AccessFlags2KHR::NONE);
+    pub const TRANSFER_READ: Self = Self::new(
+        vk::PipelineStageFlags2KHR::TRANSFER,
+        vk::AccessFlags2KHR::TRANSFER_READ,
+    );
+    pub const TRANSFER_WRITE: Self = Self::new(
+        vk::PipelineStageFlags2KHR::TRANSFER,
+        vk::AccessFlags2KHR::TRANSFER_WRITE,
+    );
+    pub const COMPUTE_SHADER_READ: Self = Self::new(
+        vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+        vk::AccessFlags2KHR::SHADER_READ,
+    );
+    pub const COMPUTE_SHADER_WRITE: Self = Self::new(
+        vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+        vk::AccessFlags2KHR::SHADER_WRITE,
+    );
+    pub const COMPUTE_UNIFORM_READ: Self = Self::new(
+        vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+        vk::AccessFlags2KHR::UNIFORM_READ,
+    );
+    pub const INDIRECT_COMMAND_READ: Self = Self::new(
+        vk::PipelineStageFlags2KHR::DRAW_INDIRECT,
+        vk::AccessFlags2KHR::INDIRECT_COMMAND_READ,
+    );
+    pub const HOST_READ: Self = Self::new(
+        vk::PipelineStageFlags2KHR::HOST,
+        vk::AccessFlags2KHR::HOST_READ,
+    );
+    pub const HOST_WRITE: Self = Self::new(
+        vk::PipelineStageFlags2KHR::HOST,
+        vk::AccessFlags2KHR::HOST_WRITE,
+    );
+    /// Everything, the safest and slowest choice.
+    pub const ALL: Self = Self::new(
+        vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+        vk::AccessFlags2KHR::from_raw(
+            vk::AccessFlags2KHR::MEMORY_READ.as_raw() | vk::AccessFlags2KHR::MEMORY_WRITE.as_raw(),
+        ),
+    );
+
+    pub const fn new(stage: vk::PipelineStageFlags2KHR, access: vk::AccessFlags2KHR) -> Self {
+        Self { stage, access }
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.access.as_raw() & !READ_ACCESSES != 0
+    }
+
+    /// Whether every stage and access of `other` is part of these ones.
+    pub(crate) fn covers(&self, other: &Access) -> bool {
+        use vk::AccessFlags2KHR as A;
+
+        let stages = self
+            .stage
+            .contains(vk::PipelineStageFlags2KHR::ALL_COMMANDS)
+            || self.stage.contains(other.stage);
+
+        let mut missing = other.access & !self.access;
+        if self.access.contains(A::MEMORY_READ) {
+            missing &= !A::from_raw(READ_ACCESSES);
+        }
+        if self.access.contains(A::MEMORY_WRITE) {
+            missing &= A::from_raw(READ_ACCESSES);
+        }
+
+        stages && missing.is_empty()
+    }
+
+    /// Stages of the core API, `empty` replaces an empty set which isn't allowed there.
+    pub(crate) fn legacy_stage(&self, empty: vk::PipelineStageFlags) -> vk::PipelineStageFlags {
+        use vk::PipelineStageFlags2KHR as S;
+
+        let mut stage = self.stage;
+        if stage.intersects(S::COPY | S::RESOLVE | S::BLIT | S::CLEAR) {
+            stage |= S::TRANSFER;
+        }
+        if stage.intersects(S::INDEX_INPUT | S::VERTEX_ATTRIBUTE_INPUT) {
+            stage |= S::VERTEX_INPUT;
+        }
+        if stage.intersects(S::PRE_RASTERIZATION_SHADERS) {
+            stage |= S::VERTEX_SHADER
+                | S::TESSELLATION_CONTROL_SHADER
+                | S::TESSELLATION_EVALUATION_SHADER
+                | S::GEOMETRY_SHADER;
+        }
+
+        // Only the new stages don't fit in 32 bits
+        let legacy = vk::PipelineStageFlags::from_raw(stage.as_raw() as u32);
+        if legacy.is_empty() {
+            empty
+        } else {
+            legacy
+        }
+    }
+
+    /// Accesses of the core API.
+    pub(crate) fn legacy_access(&self) -> vk::AccessFlags {
+        use vk::AccessFlags2KHR as A;
+
+        let mut access = self.access;
+        if access.intersects(A::SHADER_SAMPLED_READ | A::SHADER_STORAGE_READ) {
+            access |= A::SHADER_READ;
+        }
+        if access.intersects(A::SHADER_STORAGE_WRITE) {
+            access |= A::SHADER_WRITE;
+        }
+
+        // Only the new accesses don't fit in 32 bits
+        vk::AccessFlags::from_raw(access.as_raw() as u32)
+    }
+}
+
+/// Every access that only reads.
+const READ_ACCESSES: u64 = vk::AccessFlags2KHR::INDIRECT_COMMAND_READ.as_raw()
+    | vk::AccessFlags2KHR::INDEX_READ.as_raw()
+    | vk::AccessFlags2KHR::VERTEX_ATTRIBUTE_READ.as_raw()
+    | vk::AccessFlags2KHR::UNIFORM_READ.as_raw()
+    | vk::AccessFlags2KHR::INPUT_ATTACHMENT_READ.as_raw()
+    | vk::AccessFlags2KHR::SHADER_READ.as_raw()
+    | vk::AccessFlags2KHR::COLOR_ATTACHMENT_READ.as_raw()
+    | vk::AccessFlags2KHR::DEPTH_STENCIL_ATTACHMENT_READ.as_raw()
+    | vk::AccessFlags2KHR::TRANSFER_READ.as_raw()
+    | vk::AccessFlags2KHR::HOST_READ.as_raw()
+    | vk::AccessFlags2KHR::MEMORY_READ.as_raw()
+    | vk::AccessFlags2KHR::SHADER_SAMPLED_READ.as_raw()
+    | vk::AccessFlags2KHR::SHADER_STORAGE_READ.as_raw();
+
+impl BitOr for Access {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self::new(self.stage | rhs.stage, self.access | rhs.access)
+    }
+}
+
+/// Global, buffer and image barriers recorded together with
+/// [`CommandRecorder::pipeline_barrier`](crate::tasks::CommandRecorder::pipeline_barrier).
+///
+/// ```ignore
+/// rec.pipeline_barrier(
+///     PipelineBarrier::new().buffer(&buffer, Access::TRANSFER_WRITE, Access::COMPUTE_SHADER_READ),
+/// );
+/// ```
+#[derive(Default)]
+pub struct PipelineBarrier<'a> {
+    pub(crate) globals: Vec<(Access, Access)>,
+    pub(crate) buffers: Vec<BufferBarrier>,
+    pub(crate) images: Vec<ImageBarrier<'a>>,
+    pub(crate) resources: Vec<Resource>,
+}
+
+pub(crate) struct BufferBarrier {
+    pub(crate) src: Access,
+    pub(crate) dst: Access,
+    pub(crate) handle: vk::Buffer,
+    pub(crate) offset: vk::DeviceSize,
+    pub(crate) size: vk::DeviceSize,
+}
+
+pub(crate) struct ImageBarrier<'a> {
+    pub(crate) src: Access,
+    pub(crate) dst: Access,
+    pub(crate) image: &'a GpuImageHandle,
+    pub(crate) levels: Range<u32>,
+    pub(crate) layout: vk::ImageLayout,
+}
+
+/// A barrier on every array layer of one mip level, as recorded.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ImageLevelBarrier {
+    pub(crate) src: Access,
+    pub(crate) dst: Access,
+    pub(crate) image: vk::Image,
+    pub(crate) range: vk::ImageSubresourceRange,
+    pub(crate) old_layout: vk::ImageLayout,
+    pub(crate) new_layout: vk::ImageLayout,
+}
+
+impl<'a> PipelineBarrier<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make the `src` accesses to any memory available to the `dst` accesses.
+    pub fn global(mut self, src: Access, dst: Access) -> Self {
+        self.globals.push((src, dst));
+        self
+    }
+
+    /// Same as [`global`](Self::global) but limited to a buffer, or a slice of it.
+    pub fn buffer<B: Buffer>(mut self, buffer: &B, src: Access, dst: Access) -> Self {
+        self.resources.push(buffer.retained());
+        self.buffers.push(BufferBarrier {
+            src,
+            dst,
+            handle: buffer.handle(),
+            offset: buffer.byte_offset(),
+            size: buffer.size(),
+        });
+        self
+    }
+
+    /// Same as [`global`](Self::global) but limited to some mip levels of an image,
+    /// which are also transitioned to `layout`.
+    ///
+    /// Their current layout is the one they were left in earlier in the same recording, or else
+    /// by the last submission transitioning them. Commands dropped without being submitted don't
+    /// transition anything, but recordings must still be submitted in the order they are recorded.
+    pub fn image(
+        mut self,
+        image: &'a GpuImageHandle,
+        levels: Range<u32>,
+        layout: vk::ImageLayout,
+        src: Access,
+        dst: Access,
+    ) -> Self {
+        self.resources.push(image.retained());
+        self.images.push(ImageBarrier {
+            src,
+            dst,
+            image,
+            levels,
+            layout,
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.globals.is_empty() && self.buffers.is_empty() && self.images.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes() {
+        assert!(Access::TRANSFER_WRITE.is_write());
+        assert!(Access::COMPUTE_SHADER_WRITE.is_write());
+        assert!(Access::ALL.is_write());
+        assert!(!Access::TRANSFER_READ.is_write());
+        assert!(!Access::COMPUTE_UNIFORM_READ.is_write());
+        assert!(!Access::NONE.is_write());
+        assert!((Access::TRANSFER_READ | Access::TRANSFER_WRITE).is_write());
+    }
+
+    #[test]
+    fn covers_same_or_more() {
+        assert!(Access::TRANSFER_WRITE.covers(&Access::TRANSFER_WRITE));
+        assert!((Access::TRANSFER_READ | Access::TRANSFER_WRITE).covers(&Access::TRANSFER_READ));
+        assert!(!Access::TRANSFER_READ.covers(&Access::TRANSFER_WRITE));
+        assert!(Access::TRANSFER_READ.covers(&Access::NONE));
+    }
+
+    #[test]
+    fn covers_needs_the_stages() {
+        assert!(!Access::TRANSFER_READ.covers(&Access::HOST_READ));
+        assert!(!Access::COMPUTE_SHADER_READ.covers(&Access::TRANSFER_READ));
+    }
+
+    #[test]
+    fn all_commands_and_memory_accesses() {
+        for access in [
+            Access::TRANSFER_READ,
+            Access::TRANSFER_WRITE,
+            Access::COMPUTE_SHADER_WRITE,
+            Access::COMPUTE_UNIFORM_READ,
+            Access::HOST_READ,
+        ] {
+            assert!(Access::ALL.covers(&access), "{:?}", access);
+        }
+
+        let memory_read = Access::new(
+            vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+            vk::AccessFlags2KHR::MEMORY_READ,
+        );
+        assert!(memory_read.covers(&Access::COMPUTE_SHADER_READ));
+        assert!(memory_read.covers(&Access::INDIRECT_COMMAND_READ));
+        assert!(!memory_read.covers(&Access::COMPUTE_SHADER_WRITE));
+
+        let memory_write = Access::new(
+            vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+            vk::AccessFlags2KHR::MEMORY_WRITE,
+        );
+        assert!(memory_write.covers(&Access::TRANSFER_WRITE));
+        assert!(!memory_write.covers(&Access::TRANSFER_READ));
+    }
+
+    #[test]
+    fn legacy_flags() {
+        assert_eq!(
+            Access::NONE.legacy_stage(vk::PipelineStageFlags::TOP_OF_PIPE),
+            vk::PipelineStageFlags::TOP_OF_PIPE
+        );
+        let copy = Access::new(
+            vk::PipelineStageFlags2KHR::COPY,
+            vk::AccessFlags2KHR::SHADER_STORAGE_READ,
+        );
+        assert_eq!(
+            copy.legacy_stage(vk::PipelineStageFlags::TOP_OF_PIPE),
+            vk::PipelineStageFlags::TRANSFER
+        );
+        assert_eq!(copy.legacy_access(), vk::AccessFlags::SHADER_READ);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{setup::QueueWithPool, tasks::ReactorShared};
+use log::error;
+use parking_lot::{Condvar, Mutex};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Background thread flushing the pending batch of each queue once its window is over.
+pub(crate) struct SubmitBatcher {
+    shared: Arc<BatcherShared>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct BatcherShared {
+    device: ash::Device,
+    reactor: Arc<ReactorShared>,
+    queues: Vec<Arc<QueueWithPool>>,
+    window: Duration,
+    state: Mutex<BatcherState>,
+    condvar: Condvar,
+}
+
+struct BatcherState {
+    running: bool,
+    /// A batch has been opened since the last scan, its deadline isn't known yet.
+    new_batch: bool,
+}
+
+impl SubmitBatcher {
+    pub(crate) fn new(
+        device: ash::Device,
+        reactor: Arc<ReactorShared>,
+        queues: Vec<Arc<QueueWithPool>>,
+        window: Duration,
+    ) -> Self {
+        let shared = Arc::new(BatcherShared {
+            device,
+            reactor,
+            queues,
+            window,
+            state: Mutex::new(BatcherState {
+                running: true,
+                new_batch: false,
+            }),
+            condvar: Condvar::new(),
+        });
+
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("vk-async-batcher".into())
+                .spawn(move || shared.run())
+                .expect("Failed to spawn the submission batcher thread")
+        };
+
+        Self {
+            shared,
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    /// How long submissions are collected before being flushed together.
+    #[inline]
+    pub(crate) fn window(&self) -> Duration {
+        self.shared.window
+    }
+
+    /// Tell the thread that a batch has been opened.
+    pub(crate) fn notify_new_batch(&self) {
+        self.shared.state.lock().new_batch = true;
+        self.shared.condvar.notify_one();
+    }
+
+    /// Stop the thread, pending batches must be flushed by the caller.
+    pub(crate) fn stop(&self) {
+        if let Some(thread) = self.thread.lock().take() {
+            self.shared.state.lock().running = false;
+            self.shared.condvar.notify_one();
+
+            if thread.join().is_err() {
+                error!("The submission batcher thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for SubmitBatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl BatcherShared {
+    fn run(&self) {
+        let mut next_deadline: Option<Instant> = None;
+
+        loop {
+            {
+                let mut state = self.state.lock();
+                while state.running && !state.new_batch {
+                    match next_deadline {
+                        Some(deadline) => {
+                            if self.condvar.wait_until(&mut state, deadline).timed_out() {
+                                break;
+                            }
+                        }
+                        None => self.condvar.wait(&mut state),
+                    }
+                }
+                if !state.running {
+                    return;
+                }
+                state.new_batch = false;
+            }
+
+            next_deadline = self.flush_due();
+        }
+    }
+
+    /// Flush the batches whose window is over, returns when the next one will be.
+    fn flush_due(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut next_deadline = None;
+
+        for queue in &self.queues {
+            let mut state = queue.queue.lock();
+            match state.batch_deadline() {
+                Some(deadline) if deadline <= now => {
+                    let res = unsafe { queue.flush(&self.device, &self.reactor, &mut state) };
+                    if let Err(e) = res {
+                        error!("Failed to submit a batch: {}", e);
+                    }
+                }
+                Some(deadline) => {
+                    next_deadline =
+                        Some(next_deadline.map_or(deadline, |d: Instant| d.min(deadline)))
+                }
+                None => {}
+            }
+        }
+
+        next_deadline
+    }
+}
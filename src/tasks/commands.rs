@@ -1,62 +1,851 @@
-use crate::setup::QueueWithPool;
 use crate::{
-    errors::Result, mem::RawAllocation, tasks::WaitForFenceFuture, DeviceQueues, VulkanApp,
+    errors::Result,
+    mem::{format_texel_size, owned_buffer, Buffer, GpuImageHandle, ImageLayouts},
+    pipeline::{ComputePipeline, DescriptorSet},
+    setup::QueueKind,
+    tasks::{
+        barriers::{BufferBarrier, ImageLevelBarrier},
+        push_owned, Access, BufferAccess, CommandBufferLease, FenceFuture, HazardTracker,
+        ImageAccess, Owned, PipelineBarrier, RecordingGuard, Resource, TimelineFuture,
+    },
+    utils::{range_fits, ranges_overlap},
+    VulkanApp,
 };
-use ash::vk;
-use parking_lot::MutexGuard;
-use std::slice::from_ref;
+use ash::{extensions::khr::Synchronization2, vk};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ops::Range,
+    slice::from_ref,
+    sync::{atomic::Ordering, Arc},
+};
+
+/// Commands recorded in a primary command buffer, ready to be submitted.
+///
+/// Dropping it without submitting gives the command buffer back to its pool.
+pub struct RecordedCommands<'a> {
+    pub(crate) app: &'a VulkanApp,
+    queue: QueueKind,
+    cmd: CommandBufferLease,
+    /// Kept alive until the commands are executed.
+    resources: Vec<Resource>,
+    /// Timeline values to wait for on the GPU before executing.
+    waits: Vec<(vk::Semaphore, u64)>,
+    /// Layouts the mip levels of images are transitioned to, only tracked once submitted.
+    layouts: Vec<(ImageLayouts, u32, vk::ImageLayout)>,
+    /// `EXCLUSIVE` resources used, their ownership is acquired when submitting.
+    owned: Vec<Owned>,
+}
+
+impl RecordedCommands<'_> {
+    /// The queue these commands will be submitted to.
+    pub fn queue(&self) -> QueueKind {
+        self.queue
+    }
+
+    /// Only execute the commands once the submissions of `dependencies` are executed.
+    ///
+    /// The wait happens on the GPU, which avoids awaiting them before submitting,
+    /// e.g. to dispatch on the compute queue right after an upload on the transfer queue.
+    /// Commands waiting for others are never batched, but their dependencies can still be
+    /// waiting in a batch, which delays them until it is flushed.
+    pub fn after(mut self, dependencies: &[&TimelineFuture]) -> Self {
+        for dep in dependencies {
+            let timeline = dep.timeline();
+            assert!(
+                dep.value() <= timeline.last_submitted.load(Ordering::Acquire),
+                "Can only wait for work that has been submitted"
+            );
+            if dep.is_complete() {
+                continue;
+            }
+
+            match self
+                .waits
+                .iter_mut()
+                .find(|(semaphore, _)| *semaphore == timeline.semaphore)
+            {
+                Some((_, value)) => *value = (*value).max(dep.value()),
+                None => self.waits.push((timeline.semaphore, dep.value())),
+            }
+        }
+        self
+    }
+
+    /// Submit the commands, the returned future resolves once they are executed.
+    pub fn submit(self) -> Result<TimelineFuture> {
+        self.submit_signaling(vk::Fence::null())
+    }
+
+    /// Same as [`submit`](Self::submit) but the submission also signals a pooled fence.
+    pub fn submit_with_fence(mut self) -> Result<FenceFuture> {
+        let fence = Arc::new(self.app.fences.acquire()?);
+        // Only back in the pool once signaled
+        self.resources.push(Arc::clone(&fence) as Resource);
+
+        let future = self.submit_signaling(fence.fence)?;
+        fence.set_signal_pending(true);
+        Ok(FenceFuture::new(future, fence))
+    }
+
+    pub(crate) fn submit_signaling(mut self, fence: vk::Fence) -> Result<TimelineFuture> {
+        // Submitted before the commands on the same queue
+        for owned in &self.owned {
+            self.app.acquire_ownership(owned, self.queue)?;
+        }
+
+        let cmd = self.cmd.cmd;
+        // Only recycled once executed
+        self.resources.push(Arc::new(self.cmd));
+
+        let future = unsafe {
+            self.app.queues.submit_to_queue(
+                &self.app.device,
+                &self.app.reactor,
+                self.app.queues.get(self.queue),
+                from_ref(&cmd),
+                &self.waits,
+                self.resources,
+                fence,
+            )?
+        };
+
+        // Dropped or failed submissions don't transition anything
+        for (layouts, level, layout) in self.layouts {
+            layouts.lock()[level as usize] = layout;
+        }
+        Ok(future)
+    }
+
+    /// Keep a resource alive until the commands are executed.
+    pub(crate) fn retain(&mut self, resource: Resource) {
+        self.resources.push(resource);
+    }
+
+    /// Acquire the ownership of an `EXCLUSIVE` resource when submitting, `None` is ignored.
+    pub(crate) fn own(&mut self, owned: Option<Owned>) {
+        push_owned(&mut self.owned, owned);
+    }
+}
+
+/// Safe interface to record commands, only accepts resources of this crate.
+///
+/// Barriers between the commands of a recording using the same buffers are inserted automatically,
+/// from what each command declares it reads and writes. Commands of different recordings aren't
+/// synchronized with each other.
+///
+/// The same goes for the mip levels of images, which are also transitioned to the layout each
+/// command needs them in. The layouts they are left in are only tracked once the commands are submitted.
+pub struct CommandRecorder<'a> {
+    device: &'a ash::Device,
+    sync2: Option<&'a Synchronization2>,
+    queue: QueueKind,
+    cmd: vk::CommandBuffer,
+    resources: Vec<Resource>,
+    tracker: HazardTracker,
+    /// Where the layouts of the images used are tracked, their levels start in those.
+    images: HashMap<vk::Image, ImageLayouts>,
+    owned: Vec<Owned>,
+}
+
+impl CommandRecorder<'_> {
+    /// Copy the whole content of `src` at the beginning of `dst`.
+    pub fn copy_buffer<S: Buffer, T: Buffer<Item = S::Item>>(&mut self, src: &S, dst: &T) {
+        self.copy_buffer_range(src, 0..src.len(), dst, 0);
+    }
+
+    /// Copy the elements of `src` in `src_range` to `dst`, starting at element `dst_offset`.
+    ///
+    /// Both are relative to the start of the buffers, or of the slices.
+    /// They may be the same buffer as long as the ranges don't overlap.
+    pub fn copy_buffer_range<S: Buffer, T: Buffer<Item = S::Item>>(
+        &mut self,
+        src: &S,
+        src_range: Range<usize>,
+        dst: &T,
+        dst_offset: usize,
+    ) {
+        assert!(
+            src_range.start <= src_range.end && src_range.end <= src.len(),
+            "Range {:?} is out of the source",
+            src_range
+        );
+        assert!(
+            range_fits(dst_offset, src_range.len(), dst.len()),
+            "Copy would overflow the destination"
+        );
+        if src_range.is_empty() {
+            return;
+        }
+
+        let item_size = std::mem::size_of::<S::Item>() as vk::DeviceSize;
+        let copy = vk::BufferCopy::builder()
+            .size(src_range.len() as vk::DeviceSize * item_size)
+            .src_offset(src.byte_offset() + src_range.start as vk::DeviceSize * item_size)
+            .dst_offset(dst.byte_offset() + dst_offset as vk::DeviceSize * item_size);
+        assert!(
+            src.handle() != dst.handle()
+                || !ranges_overlap(copy.src_offset, copy.dst_offset, copy.size),
+            "Source and destination of a copy within the same buffer overlap"
+        );
+
+        self.use_buffer(src);
+        self.use_buffer(dst);
+        self.track(&[
+            BufferAccess {
+                handle: src.handle(),
+                offset: copy.src_offset,
+                size: copy.size,
+                access: Access::TRANSFER_READ,
+            },
+            BufferAccess {
+                handle: dst.handle(),
+                offset: copy.dst_offset,
+                size: copy.size,
+                access: Access::TRANSFER_WRITE,
+            },
+        ]);
+        unsafe {
+            self.device
+                .cmd_copy_buffer(self.cmd, src.handle(), dst.handle(), from_ref(&copy));
+        }
+    }
+
+    /// Copy a buffer, or slice, to a whole mip level of `image`, every array layer.
+    ///
+    /// The buffer must hold exactly [`level_size`](crate::mem::ImageDesc::level_size) bytes with
+    /// the layers one after the other, and start at a multiple of 4 and of the texel size.
+    /// Depth and stencil images can only be copied on the graphics queue.
+    pub fn copy_buffer_to_image<B: Buffer>(&mut self, src: &B, dst: &GpuImageHandle, level: u32) {
+        let copy = self.level_copy(src, dst, level);
+        self.use_buffer(src);
+        self.resources.push(dst.retained());
+        self.use_image(dst);
+        self.track_images(
+            &[BufferAccess {
+                handle: src.handle(),
+                offset: copy.buffer_offset,
+                size: src.size(),
+                access: Access::TRANSFER_READ,
+            }],
+            &[ImageAccess {
+                image: dst.handle(),
+                range: dst.level_range(level),
+                layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                access: Access::TRANSFER_WRITE,
+            }],
+        );
+        unsafe {
+            self.device.cmd_copy_buffer_to_image(
+                self.cmd,
+                src.handle(),
+                dst.handle(),
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                from_ref(&copy),
+            );
+        }
+    }
+
+    /// Copy a whole mip level of `image`, every array layer, to a buffer or slice.
+    ///
+    /// Same requirements as [`copy_buffer_to_image`](Self::copy_buffer_to_image).
+    pub fn copy_image_to_buffer<B: Buffer>(&mut self, src: &GpuImageHandle, level: u32, dst: &B) {
+        let copy = self.level_copy(dst, src, level);
+        self.resources.push(src.retained());
+        self.use_buffer(dst);
+        self.use_image(src);
+        self.track_images(
+            &[BufferAccess {
+                handle: dst.handle(),
+                offset: copy.buffer_offset,
+                size: dst.size(),
+                access: Access::TRANSFER_WRITE,
+            }],
+            &[ImageAccess {
+                image: src.handle(),
+                range: src.level_range(level),
+                layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                access: Access::TRANSFER_READ,
+            }],
+        );
+        unsafe {
+            self.device.cmd_copy_image_to_buffer(
+                self.cmd,
+                src.handle(),
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                dst.handle(),
+                from_ref(&copy),
+            );
+        }
+    }
+
+    /// Fill the whole buffer, or slice, with the same 4 bytes repeated.
+    ///
+    /// The offset and size of a slice must be multiples of 4, a whole buffer is filled
+    /// up to its last multiple of 4 bytes.
+    pub fn fill_buffer<B: Buffer>(&mut self, dst: &B, data: u32) {
+        if dst.size() == 0 {
+            return;
+        }
+        let size = if dst.byte_offset() == 0 && dst.size() == dst.whole_size() {
+            vk::WHOLE_SIZE
+        } else {
+            assert_eq!(
+                dst.byte_offset() % 4,
+                0,
+                "Fill offset must be a multiple of 4"
+            );
+            assert_eq!(dst.size() % 4, 0, "Fill size must be a multiple of 4");
+            dst.size()
+        };
+
+        self.use_buffer(dst);
+        self.track(&[BufferAccess::of(dst, Access::TRANSFER_WRITE)]);
+        unsafe {
+            self.device
+                .cmd_fill_buffer(self.cmd, dst.handle(), dst.byte_offset(), size, data);
+        }
+    }
+
+    /// Write a small amount of data inline in the command buffer at the start of `dst`.
+    ///
+    /// Limited to 65536 bytes by Vulkan and the size must be a multiple of 4.
+    pub fn update_buffer<B: Buffer>(&mut self, dst: &B, data: &[B::Item]) {
+        let size = std::mem::size_of_val(data) as vk::DeviceSize;
+        assert!(size <= dst.size(), "Update would overflow the destination");
+        assert!(size <= 65536, "Too much data for an inline update");
+        assert_eq!(size % 4, 0, "Inline updates must be a multiple of 4 bytes");
+        assert_eq!(
+            dst.byte_offset() % 4,
+            0,
+            "Inline updates must start at a multiple of 4 bytes"
+        );
+
+        let bytes =
+            unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, size as usize) };
+
+        self.use_buffer(dst);
+        self.track(&[BufferAccess {
+            size,
+            ..BufferAccess::of(dst, Access::TRANSFER_WRITE)
+        }]);
+        unsafe {
+            self.device
+                .cmd_update_buffer(self.cmd, dst.handle(), dst.byte_offset(), bytes);
+        }
+    }
+
+    /// Bind the pipeline with its descriptor sets and run it with the given amount of workgroups.
+    pub fn dispatch(
+        &mut self,
+        pipeline: &ComputePipeline,
+        sets: &[&DescriptorSet],
+        x: u32,
+        y: u32,
+        z: u32,
+    ) {
+        assert_ne!(
+            self.queue,
+            QueueKind::Transfer,
+            "Can't dispatch on the transfer queue"
+        );
+        assert_eq!(
+            sets.len(),
+            pipeline.objects.set_layouts.len(),
+            "Every set of the pipeline must be bound"
+        );
+
+        let mut handles = vec![vk::DescriptorSet::null(); sets.len()];
+        let mut accesses = Vec::new();
+        for set in sets {
+            assert_eq!(
+                set.layout, pipeline.objects.set_layouts[set.index as usize],
+                "Descriptor set created for another pipeline"
+            );
+            handles[set.index as usize] = set.set;
+            accesses.extend_from_slice(&set.accesses);
+            self.resources.push(Arc::clone(&set.objects) as Resource);
+            for owned in &set.owned {
+                push_owned(&mut self.owned, Some(owned.clone()));
+            }
+            for in_flight in &set.in_flight {
+                self.resources
+                    .push(in_flight.track(Arc::clone(&set.objects) as Resource));
+            }
+        }
+        self.resources
+            .push(Arc::clone(&pipeline.objects) as Resource);
+        self.track(&accesses);
+
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                self.cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline.objects.pipeline,
+            );
+            if !handles.is_empty() {
+                self.device.cmd_bind_descriptor_sets(
+                    self.cmd,
+                    vk::PipelineBindPoint::COMPUTE,
+                    pipeline.objects.layout,
+                    0,
+                    &handles,
+                    &[],
+                );
+            }
+            self.device.cmd_dispatch(self.cmd, x, y, z);
+        }
+    }
+
+    /// Set the push constants of a pipeline, must match the size declared in the shader.
+    pub fn push_constants<T: Copy>(&mut self, pipeline: &ComputePipeline, data: &T) {
+        let size = std::mem::size_of::<T>() as u32;
+        assert_eq!(
+            size, pipeline.reflection.push_constants_size,
+            "Push constants size doesn't match the shader"
+        );
+
+        let bytes = unsafe { std::slice::from_raw_parts(data as *const T as *const u8, size as _) };
+
+        self.resources
+            .push(Arc::clone(&pipeline.objects) as Resource);
+        unsafe {
+            self.device.cmd_push_constants(
+                self.cmd,
+                pipeline.objects.layout,
+                pipeline.reflection.stage,
+                0,
+                bytes,
+            );
+        }
+    }
+
+    /// Insert a global memory barrier between two sets of pipeline stages.
+    pub fn memory_barrier(
+        &mut self,
+        src_stage: vk::PipelineStageFlags,
+        src_access: vk::AccessFlags,
+        dst_stage: vk::PipelineStageFlags,
+        dst_access: vk::AccessFlags,
+    ) {
+        let access = |stage: vk::PipelineStageFlags, access: vk::AccessFlags| {
+            Access::new(
+                vk::PipelineStageFlags2KHR::from_raw(stage.as_raw() as _),
+                vk::AccessFlags2KHR::from_raw(access.as_raw() as _),
+            )
+        };
+
+        self.pipeline_barrier(
+            PipelineBarrier::new()
+                .global(access(src_stage, src_access), access(dst_stage, dst_access)),
+        );
+    }
+
+    /// Make the `src` accesses to a buffer, or a slice, available to the `dst` accesses.
+    pub fn buffer_barrier<B: Buffer>(&mut self, buffer: &B, src: Access, dst: Access) {
+        self.pipeline_barrier(PipelineBarrier::new().buffer(buffer, src, dst));
+    }
+
+    /// Make a buffer written by a transfer visible to compute shader reads.
+    pub fn transfer_write_to_compute_read<B: Buffer>(&mut self, buffer: &B) {
+        self.buffer_barrier(buffer, Access::TRANSFER_WRITE, Access::COMPUTE_SHADER_READ);
+    }
+
+    /// Make a buffer written by a compute shader visible to compute shader reads, e.g. between dispatches.
+    pub fn compute_write_to_compute_read<B: Buffer>(&mut self, buffer: &B) {
+        self.buffer_barrier(
+            buffer,
+            Access::COMPUTE_SHADER_WRITE,
+            Access::COMPUTE_SHADER_READ,
+        );
+    }
+
+    /// Make a buffer written by a compute shader visible to transfer reads, e.g. before a readback.
+    pub fn compute_write_to_transfer_read<B: Buffer>(&mut self, buffer: &B) {
+        self.buffer_barrier(buffer, Access::COMPUTE_SHADER_WRITE, Access::TRANSFER_READ);
+    }
+
+    /// Record barriers, with `VK_KHR_synchronization2` when the device supports it.
+    ///
+    /// Without it the stages of every barrier are merged into a single `vkCmdPipelineBarrier`.
+    pub fn pipeline_barrier(&mut self, barrier: PipelineBarrier<'_>) {
+        if barrier.is_empty() {
+            return;
+        }
+
+        // One barrier per mip level, they don't necessarily share the same layout
+        let mut images = Vec::new();
+        for image in &barrier.images {
+            image.image.check_levels(&image.levels);
+            self.use_image(image.image);
+            for level in image.levels.clone() {
+                let access = ImageAccess {
+                    image: image.image.handle(),
+                    range: image.image.level_range(level),
+                    layout: image.layout,
+                    access: image.dst,
+                };
+                let old_layout =
+                    self.tracker
+                        .image_barrier(&access, image.src, current_layout(&self.images));
+                images.push(ImageLevelBarrier {
+                    src: image.src,
+                    dst: image.dst,
+                    image: access.image,
+                    range: access.range,
+                    old_layout,
+                    new_layout: image.layout,
+                });
+            }
+        }
+        self.resources.extend(barrier.resources);
+
+        for (src, dst) in &barrier.globals {
+            self.tracker.barrier(None, *src, *dst);
+        }
+        for b in &barrier.buffers {
+            self.tracker.barrier(Some(b.handle), b.src, b.dst);
+        }
+
+        self.record_barriers(&barrier.globals, &barrier.buffers, &images);
+    }
+
+    /// Keep `buffer` alive and in flight until the commands are executed.
+    fn use_buffer<B: Buffer>(&mut self, buffer: &B) {
+        self.resources.push(buffer.used());
+        push_owned(&mut self.owned, owned_buffer(buffer));
+    }
+
+    /// Start tracking the layouts of `image` if it's the first time the recording uses it.
+    fn use_image(&mut self, image: &GpuImageHandle) {
+        if let Entry::Vacant(entry) = self.images.entry(image.handle()) {
+            entry.insert(Arc::clone(image.layouts()));
+            push_owned(&mut self.owned, image.owned());
+        }
+    }
+
+    /// Record the barriers needed before a command making `accesses`.
+    fn track(&mut self, accesses: &[BufferAccess]) {
+        let barriers = self.tracker.access(accesses);
+        if !barriers.is_empty() {
+            self.record_barriers(&[], &barriers, &[]);
+        }
+    }
+
+    /// Record the barriers and layout transitions needed before a command making both buffer
+    /// and image accesses, the images must have been [used](Self::use_image).
+    fn track_images(&mut self, buffers: &[BufferAccess], images: &[ImageAccess]) {
+        let buffers = self.tracker.access(buffers);
+        let images = self
+            .tracker
+            .access_images(images, current_layout(&self.images));
+        if !buffers.is_empty() || !images.is_empty() {
+            self.record_barriers(&[], &buffers, &images);
+        }
+    }
+
+    /// Region copying a whole mip level from or to `buffer`, panics if they don't match.
+    fn level_copy<B: Buffer>(
+        &self,
+        buffer: &B,
+        image: &GpuImageHandle,
+        level: u32,
+    ) -> vk::BufferImageCopy {
+        let desc = image.desc();
+        image.check_levels(&(level..level + 1));
+        assert!(
+            desc.aspect() == vk::ImageAspectFlags::COLOR || self.queue == QueueKind::Graphics,
+            "Depth and stencil images can only be copied on the graphics queue"
+        );
+        let size = desc
+            .level_size(level)
+            .unwrap_or_else(|err| panic!("Can't copy mip level {}: {}", level, err));
+        assert_eq!(
+            buffer.size(),
+            size,
+            "Buffer doesn't match the size of the mip level"
+        );
+        let texel = format_texel_size(desc.format).unwrap_or(1) as vk::DeviceSize;
+        assert_eq!(
+            buffer.byte_offset() % 4,
+            0,
+            "Buffer offset must be a multiple of 4"
+        );
+        assert_eq!(
+            buffer.byte_offset() % texel,
+            0,
+            "Buffer offset must be a multiple of the texel size"
+        );
+
+        let mut copy = image.level_copy(level);
+        copy.buffer_offset = buffer.byte_offset();
+        copy
+    }
+
+    /// Record barriers, with their layout transitions for images.
+    fn record_barriers(
+        &self,
+        globals: &[(Access, Access)],
+        buffers: &[BufferBarrier],
+        images: &[ImageLevelBarrier],
+    ) {
+        match self.sync2 {
+            Some(sync2) => {
+                let globals: Vec<_> = globals
+                    .iter()
+                    .map(|(src, dst)| {
+                        vk::MemoryBarrier2KHR::builder()
+                            .src_stage_mask(src.stage)
+                            .src_access_mask(src.access)
+                            .dst_stage_mask(dst.stage)
+                            .dst_access_mask(dst.access)
+                            .build()
+                    })
+                    .collect();
+                let buffers: Vec<_> = buffers
+                    .iter()
+                    .map(|b| {
+                        vk::BufferMemoryBarrier2KHR::builder()
+                            .src_stage_mask(b.src.stage)
+                            .src_access_mask(b.src.access)
+                            .dst_stage_mask(b.dst.stage)
+                            .dst_access_mask(b.dst.access)
+                            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .buffer(b.handle)
+                            .offset(b.offset)
+                            .size(b.size)
+                            .build()
+                    })
+                    .collect();
+                let images: Vec<_> = images
+                    .iter()
+                    .map(|image| {
+                        vk::ImageMemoryBarrier2KHR::builder()
+                            .src_stage_mask(image.src.stage)
+                            .src_access_mask(image.src.access)
+                            .dst_stage_mask(image.dst.stage)
+                            .dst_access_mask(image.dst.access)
+                            .old_layout(image.old_layout)
+                            .new_layout(image.new_layout)
+                            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .image(image.image)
+                            .subresource_range(image.range)
+                            .build()
+                    })
+                    .collect();
+
+                unsafe {
+                    sync2.cmd_pipeline_barrier2(
+                        self.cmd,
+                        &vk::DependencyInfoKHR::builder()
+                            .memory_barriers(&globals)
+                            .buffer_memory_barriers(&buffers)
+                            .image_memory_barriers(&images),
+                    );
+                }
+            }
+            None => {
+                let mut src_stage = vk::PipelineStageFlags::empty();
+                let mut dst_stage = vk::PipelineStageFlags::empty();
+                let mut stages = |src: &Access, dst: &Access| {
+                    src_stage |= src.legacy_stage(vk::PipelineStageFlags::TOP_OF_PIPE);
+                    dst_stage |= dst.legacy_stage(vk::PipelineStageFlags::BOTTOM_OF_PIPE);
+                };
+
+                let globals: Vec<_> = globals
+                    .iter()
+                    .map(|(src, dst)| {
+                        stages(src, dst);
+                        vk::MemoryBarrier::builder()
+                            .src_access_mask(src.legacy_access())
+                            .dst_access_mask(dst.legacy_access())
+                            .build()
+                    })
+                    .collect();
+                let buffers: Vec<_> = buffers
+                    .iter()
+                    .map(|b| {
+                        stages(&b.src, &b.dst);
+                        vk::BufferMemoryBarrier::builder()
+                            .src_access_mask(b.src.legacy_access())
+                            .dst_access_mask(b.dst.legacy_access())
+                            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .buffer(b.handle)
+                            .offset(b.offset)
+                            .size(b.size)
+                            .build()
+                    })
+                    .collect();
+                let images: Vec<_> = images
+                    .iter()
+                    .map(|image| {
+                        stages(&image.src, &image.dst);
+                        vk::ImageMemoryBarrier::builder()
+                            .src_access_mask(image.src.legacy_access())
+                            .dst_access_mask(image.dst.legacy_access())
+                            .old_layout(image.old_layout)
+                            .new_layout(image.new_layout)
+                            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .image(image.image)
+                            .subresource_range(image.range)
+                            .build()
+                    })
+                    .collect();
+
+                unsafe {
+                    self.device.cmd_pipeline_barrier(
+                        self.cmd,
+                        src_stage,
+                        dst_stage,
+                        vk::DependencyFlags::empty(),
+                        &globals,
+                        &buffers,
+                        &images,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Layout of the mip levels of `images` a recording hasn't used yet.
+fn current_layout(
+    images: &HashMap<vk::Image, ImageLayouts>,
+) -> impl Fn(vk::Image, u32) -> vk::ImageLayout + '_ {
+    move |image, level| images[&image].lock()[level as usize]
+}
 
 impl VulkanApp {
-    pub(crate) unsafe fn cmd_copy_buffer(
+    /// Record commands to later submit them to the given queue.
+    ///
+    /// `recorder` may itself record other commands, e.g. to submit an upload they depend on.
+    ///
+    /// ```ignore
+    /// app.record(QueueKind::Transfer, |rec| {
+    ///     rec.copy_buffer(&src, &dst);
+    /// })?
+    /// .submit()?
+    /// .await?;
+    /// ```
+    pub fn record(
         &self,
-        src: (vk::Buffer, &RawAllocation),
-        dst: (vk::Buffer, &RawAllocation),
-    ) -> Result<WaitForFenceFuture> {
-        self.execute_commands(
-            |qs| qs.transfer(),
-            |pool| Ok(self.allocate_primary_buffers_from_pool(pool, 1)?[0]),
-            |device, cmd| {
-                device.begin_command_buffer(
+        queue: QueueKind,
+        recorder: impl FnOnce(&mut CommandRecorder),
+    ) -> Result<RecordedCommands<'_>> {
+        let mut resources = Vec::new();
+        let mut layouts = Vec::new();
+        let mut owned = Vec::new();
+        let mut commands = unsafe {
+            self.record_commands(queue, |device, cmd| {
+                let mut rec = CommandRecorder {
+                    device,
+                    sync2: self.synchronization2.as_ref(),
+                    queue,
                     cmd,
-                    &vk::CommandBufferBeginInfo::builder()
-                        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-                )?;
+                    resources: Vec::new(),
+                    tracker: HazardTracker::default(),
+                    images: HashMap::new(),
+                    owned: Vec::new(),
+                };
+                recorder(&mut rec);
+                layouts = rec
+                    .tracker
+                    .layouts()
+                    .map(|((image, level), layout)| {
+                        (Arc::clone(&rec.images[&image]), level, layout)
+                    })
+                    .collect();
+                resources = rec.resources;
+                owned = rec.owned;
+                Ok(())
+            })?
+        };
+        commands.resources = resources;
+        commands.layouts = layouts;
+        commands.owned = owned;
+        Ok(commands)
+    }
+
+    /// Submit `commands` once the submissions of `dependencies` are executed, without waiting on the CPU.
+    ///
+    /// ```ignore
+    /// let upload = app.record(QueueKind::Transfer, |rec| rec.copy_buffer(&src, &dst))?.submit()?;
+    /// let compute = app.record(QueueKind::Compute, |rec| rec.dispatch(&pipeline, &[&set], x, 1, 1))?;
+    /// app.submit_after(&[&upload], compute)?.await?;
+    /// ```
+    pub fn submit_after(
+        &self,
+        dependencies: &[&TimelineFuture],
+        commands: RecordedCommands<'_>,
+    ) -> Result<TimelineFuture> {
+        commands.after(dependencies).submit()
+    }
 
-                let copy = vk::BufferCopy::builder()
-                    .size(src.1.size)
-                    .src_offset(0)
-                    .dst_offset(0);
+    /// Copy `size` bytes between two buffers on the transfer queue.
+    ///
+    /// `retain` is kept alive until the copy is done, the ownership of `owned` is acquired first.
+    pub(crate) unsafe fn cmd_copy_buffer(
+        &self,
+        src: (vk::Buffer, vk::DeviceSize),
+        dst: (vk::Buffer, vk::DeviceSize),
+        size: vk::DeviceSize,
+        retain: Resource,
+        owned: Option<Owned>,
+    ) -> Result<TimelineFuture> {
+        let mut commands = self.record_commands(QueueKind::Transfer, |device, cmd| {
+            let copy = vk::BufferCopy::builder()
+                .size(size)
+                .src_offset(src.1)
+                .dst_offset(dst.1);
 
-                device.cmd_copy_buffer(cmd, src.0, dst.0, from_ref(&copy));
-                Ok(())
-            },
-        )
+            device.cmd_copy_buffer(cmd, src.0, dst.0, from_ref(&copy));
+            Ok(())
+        })?;
+        commands.retain(retain);
+        commands.own(owned);
+        commands.submit()
     }
 
-    #[inline]
-    pub(crate) unsafe fn execute_commands(
+    /// Record a one time command buffer from the pool of the calling thread.
+    /// The pool stays locked the whole time, `recorder` may record other commands meanwhile.
+    pub(crate) unsafe fn record_commands(
         &self,
-        queue_chooser: impl FnOnce(&DeviceQueues) -> &QueueWithPool,
-        cmd_creator: impl FnOnce(vk::CommandPool) -> Result<vk::CommandBuffer>,
+        queue: QueueKind,
         recorder: impl FnOnce(&ash::Device, vk::CommandBuffer) -> Result<()>,
-    ) -> Result<WaitForFenceFuture> {
-        let queue_pool = queue_chooser(&self.queues);
+    ) -> Result<RecordedCommands<'_>> {
+        if let Some(e) = self.reactor.shared().error() {
+            return Err(e.into());
+        }
 
-        let cmd = {
-            let pool = queue_pool.pool.lock();
-            let cmd = cmd_creator(*pool)?;
-            recorder(&self.device, cmd);
+        let thread_pool = self.queues.get(queue).pools.current(&self.device)?;
+        let pool = thread_pool.pool.lock();
+        // Reset with the pool still locked if the recorder fails or panics
+        let recording = RecordingGuard::new(thread_pool.acquire(&self.device, *pool)?, |cmd| {
+            let _ = self
+                .device
+                .reset_command_buffer(cmd, vk::CommandBufferResetFlags::empty());
+        });
+        let cmd = recording.cmd();
 
-            unsafe {
-                self.device.end_command_buffer(cmd)?;
-            }
-            cmd
-        };
+        self.device.begin_command_buffer(
+            cmd,
+            &vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+        )?;
+        recorder(&self.device, cmd)?;
+        self.device.end_command_buffer(cmd)?;
 
-        self.queues.submit_to_queue(
-            &self.device,
-            &queue_pool.queue,
-            from_ref(&vk::SubmitInfo::builder().command_buffers(from_ref(&cmd))),
-        )
+        Ok(RecordedCommands {
+            app: self,
+            queue,
+            cmd: recording.finish(),
+            resources: Vec::new(),
+            waits: Vec::new(),
+            layouts: Vec::new(),
+            owned: Vec::new(),
+        })
     }
 }
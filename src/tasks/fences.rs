@@ -0,0 +1,198 @@
+use crate::{errors::Result, tasks::TimelineFuture};
+use ash::vk;
+use log::error;
+use parking_lot::Mutex;
+use std::{
+    future::Future,
+    pin::Pin,
+    slice::from_ref,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Counters of the fence pool of the app, see [`VulkanApp::fence_pool_stats`](crate::VulkanApp::fence_pool_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FencePoolStats {
+    /// Fences created since the start.
+    pub created: u64,
+    /// Times a fence has been reused instead of created.
+    pub reused: u64,
+    /// Fences currently used by a submission or a [`FenceFuture`].
+    pub in_use: u64,
+    /// Fences ready to be reused.
+    pub idle: usize,
+}
+
+/// How long to wait for a fence whose submission reached its timeline value, in nanoseconds.
+const FENCE_SIGNAL_TIMEOUT: u64 = 1_000_000_000;
+
+/// Recycles fences, they are reset when handed out again.
+pub(crate) struct FencePool {
+    device: ash::Device,
+    /// Fences are created exportable as sync_fd.
+    exportable: bool,
+    /// `None` once destroyed, fences given back after that are left alone.
+    free: Mutex<Option<Vec<vk::Fence>>>,
+    created: AtomicU64,
+    reused: AtomicU64,
+    in_use: AtomicU64,
+}
+
+impl FencePool {
+    pub(crate) fn new(device: ash::Device, exportable: bool) -> Self {
+        Self {
+            device,
+            exportable,
+            free: Mutex::new(Some(Vec::new())),
+            created: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+            in_use: AtomicU64::new(0),
+        }
+    }
+
+    /// Get an unsignaled fence, given back when the returned value is dropped.
+    pub(crate) fn acquire(self: &Arc<Self>) -> Result<PooledFence> {
+        let recycled = self.free.lock().as_mut().and_then(|free| free.pop());
+
+        let fence = match recycled {
+            Some(fence) => {
+                if let Err(e) = unsafe { self.device.reset_fences(from_ref(&fence)) } {
+                    unsafe { self.device.destroy_fence(fence, None) };
+                    return Err(e.into());
+                }
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                fence
+            }
+            None => {
+                let mut export = vk::ExportFenceCreateInfo::builder()
+                    .handle_types(vk::ExternalFenceHandleTypeFlags::SYNC_FD);
+                let mut create_info = vk::FenceCreateInfo::builder();
+                if self.exportable {
+                    create_info = create_info.push_next(&mut export);
+                }
+
+                let fence = unsafe { self.device.create_fence(&create_info, None)? };
+                self.created.fetch_add(1, Ordering::Relaxed);
+                fence
+            }
+        };
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+
+        Ok(PooledFence {
+            pool: Arc::clone(self),
+            fence,
+            signal_pending: AtomicBool::new(false),
+        })
+    }
+
+    /// Wait for the signal operation pending on `fence`, it can't be reset or destroyed before.
+    ///
+    /// The timeline value of its submission being reached doesn't mean the fence is signaled yet.
+    fn wait_signaled(&self, fence: vk::Fence) -> bool {
+        let res = unsafe {
+            self.device
+                .wait_for_fences(from_ref(&fence), true, FENCE_SIGNAL_TIMEOUT)
+        };
+        match res {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Leaking a fence that its submission didn't signal: {}", e);
+                false
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> FencePoolStats {
+        FencePoolStats {
+            created: self.created.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            idle: self.free.lock().as_ref().map_or(0, Vec::len),
+        }
+    }
+
+    /// Destroy the idle fences, the device must be idle.
+    pub(crate) unsafe fn destroy(&self) {
+        if let Some(free) = self.free.lock().take() {
+            for fence in free {
+                self.device.destroy_fence(fence, None);
+            }
+        }
+    }
+}
+
+/// A fence borrowed from the pool.
+///
+/// Submissions retain it, so it only goes back to the pool once signaled and unused.
+pub(crate) struct PooledFence {
+    pool: Arc<FencePool>,
+    pub(crate) fence: vk::Fence,
+    /// A submission has been made signaling it, and its payload hasn't been exported since.
+    signal_pending: AtomicBool,
+}
+
+impl PooledFence {
+    /// Call once a submission signaling the fence succeeded, or once its payload is exported.
+    pub(crate) fn set_signal_pending(&self, pending: bool) {
+        self.signal_pending.store(pending, Ordering::Release);
+    }
+}
+
+impl Drop for PooledFence {
+    fn drop(&mut self) {
+        self.pool.in_use.fetch_sub(1, Ordering::Relaxed);
+        // Leaked if it never gets signaled, it can't be destroyed either
+        if self.signal_pending.load(Ordering::Acquire) && !self.pool.wait_signaled(self.fence) {
+            return;
+        }
+        if let Some(free) = self.pool.free.lock().as_mut() {
+            free.push(self.fence);
+        }
+    }
+}
+
+/// A [`TimelineFuture`] whose submission also signals a fence, e.g. to share it with other APIs.
+///
+/// The fence goes back to the pool once both the submission and this future are done with it,
+/// dropping the future early never leaks it.
+pub struct FenceFuture {
+    inner: TimelineFuture,
+    fence: Arc<PooledFence>,
+}
+
+impl FenceFuture {
+    pub(crate) fn new(inner: TimelineFuture, fence: Arc<PooledFence>) -> Self {
+        Self { inner, fence }
+    }
+
+    /// The fence signaled by the submission, only valid while this future is alive.
+    pub fn fence(&self) -> vk::Fence {
+        self.fence.fence
+    }
+
+    /// The timeline future of the same submission.
+    pub fn timeline_future(&self) -> &TimelineFuture {
+        &self.inner
+    }
+
+    /// See [`TimelineFuture::with_timeout`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.inner = self.inner.with_timeout(timeout);
+        self
+    }
+
+    /// Let the submission complete on its own, the fence goes back to the pool once it's signaled.
+    pub fn detach(self) {}
+}
+
+impl Future for FenceFuture {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.inner).poll(ctx)
+    }
+}
@@ -0,0 +1,619 @@
+use crate::{
+    mem::Buffer,
+    tasks::{
+        barriers::{BufferBarrier, ImageLevelBarrier},
+        Access,
+    },
+};
+use ash::vk;
+use std::collections::HashMap;
+
+/// A range of a buffer used by a command.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BufferAccess {
+    pub(crate) handle: vk::Buffer,
+    pub(crate) offset: vk::DeviceSize,
+    pub(crate) size: vk::DeviceSize,
+    pub(crate) access: Access,
+}
+
+impl BufferAccess {
+    /// The whole buffer, or slice.
+    pub(crate) fn of<B: Buffer>(buffer: &B, access: Access) -> Self {
+        Self {
+            handle: buffer.handle(),
+            offset: buffer.byte_offset(),
+            size: buffer.size(),
+            access,
+        }
+    }
+
+    fn merge(&mut self, other: &BufferAccess) {
+        let end = (self.offset + self.size).max(other.offset + other.size);
+        self.offset = self.offset.min(other.offset);
+        self.size = end - self.offset;
+        self.access = self.access | other.access;
+    }
+}
+
+/// Every array layer of one mip level of an image used by a command, which needs it in `layout`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ImageAccess {
+    pub(crate) image: vk::Image,
+    pub(crate) range: vk::ImageSubresourceRange,
+    pub(crate) layout: vk::ImageLayout,
+    pub(crate) access: Access,
+}
+
+impl ImageAccess {
+    fn key(&self) -> (vk::Image, u32) {
+        (self.image, self.range.base_mip_level)
+    }
+}
+
+/// What happened to a buffer, or a mip level, since it was last written.
+#[derive(Clone, Copy)]
+struct AccessState {
+    /// The last write, `Access::NONE` if there was none yet.
+    write: Access,
+    /// What the last write has already been made visible to.
+    visible: Access,
+    /// Stages that read it since the last write.
+    reads: vk::PipelineStageFlags2KHR,
+}
+
+/// A mip level of an image, its layout also changes during the recording.
+struct ImageState {
+    layout: vk::ImageLayout,
+    access: AccessState,
+}
+
+/// Last accesses to every buffer and image used by a recording, to derive the barriers needed
+/// between its commands.
+///
+/// Buffers are tracked as a whole, accesses to disjoint ranges of the same buffer are still synchronized.
+/// Images are tracked per mip level, along with their layout: using one in another layout than
+/// its current one inserts the transition.
+///
+/// A recording only targets one queue, the ownership of `EXCLUSIVE` resources is transferred to
+/// it when submitted, not by the recorded barriers.
+#[derive(Default)]
+pub(crate) struct HazardTracker {
+    buffers: HashMap<vk::Buffer, AccessState>,
+    images: HashMap<(vk::Image, u32), ImageState>,
+}
+
+impl HazardTracker {
+    /// Barriers to record before a command making `accesses`, which are then remembered.
+    pub(crate) fn access(&mut self, accesses: &[BufferAccess]) -> Vec<BufferBarrier> {
+        // A command can use the same buffer several times, e.g. copying within it
+        let mut merged: Vec<BufferAccess> = Vec::with_capacity(accesses.len());
+        for access in accesses {
+            match merged.iter_mut().find(|m| m.handle == access.handle) {
+                Some(m) => m.merge(access),
+                None => merged.push(*access),
+            }
+        }
+
+        let mut barriers = Vec::new();
+        for access in merged {
+            let dst = access.access;
+            let src = self
+                .buffers
+                .entry(access.handle)
+                .or_insert(AccessState::UNUSED)
+                .access(dst);
+
+            if !src.stage.is_empty() {
+                barriers.push(BufferBarrier {
+                    src,
+                    dst,
+                    handle: access.handle,
+                    offset: access.offset,
+                    size: access.size,
+                });
+            }
+        }
+
+        barriers
+    }
+
+    /// Barriers, and layout transitions, to record before a command making `accesses`.
+    ///
+    /// `current` is the layout of the mip levels the recording hasn't used yet.
+    pub(crate) fn access_images(
+        &mut self,
+        accesses: &[ImageAccess],
+        current: impl Fn(vk::Image, u32) -> vk::ImageLayout,
+    ) -> Vec<ImageLevelBarrier> {
+        let mut barriers = Vec::new();
+        for access in accesses {
+            let (image, level) = access.key();
+            let state = self
+                .images
+                .entry((image, level))
+                .or_insert_with(|| ImageState {
+                    layout: current(image, level),
+                    access: AccessState::UNUSED,
+                });
+
+            let dst = access.access;
+            let src = if state.layout != access.layout {
+                // The transition reads and writes the whole level
+                let src = state.access.write | state.access.reads_only();
+                state.access = AccessState::transitioned(dst);
+                src
+            } else {
+                state.access.access(dst)
+            };
+
+            if !src.stage.is_empty() || state.layout != access.layout {
+                barriers.push(ImageLevelBarrier {
+                    src,
+                    dst,
+                    image,
+                    range: access.range,
+                    old_layout: state.layout,
+                    new_layout: access.layout,
+                });
+                state.layout = access.layout;
+            }
+        }
+
+        barriers
+    }
+
+    /// Remember a barrier recorded explicitly, `buffer` is `None` for global barriers.
+    ///
+    /// Reads are kept, writes coming after them may still get a redundant execution barrier.
+    pub(crate) fn barrier(&mut self, buffer: Option<vk::Buffer>, src: Access, dst: Access) {
+        match buffer {
+            Some(handle) => {
+                if let Some(state) = self.buffers.get_mut(&handle) {
+                    state.barrier(src, dst);
+                }
+            }
+            None => {
+                for state in self.buffers.values_mut() {
+                    state.barrier(src, dst);
+                }
+                for state in self.images.values_mut() {
+                    state.access.barrier(src, dst);
+                }
+            }
+        }
+    }
+
+    /// Remember a barrier on a mip level recorded explicitly, which also transitions it to
+    /// `access.layout`. Returns the layout it was in.
+    pub(crate) fn image_barrier(
+        &mut self,
+        access: &ImageAccess,
+        src: Access,
+        current: impl Fn(vk::Image, u32) -> vk::ImageLayout,
+    ) -> vk::ImageLayout {
+        let (image, level) = access.key();
+        let state = self
+            .images
+            .entry((image, level))
+            .or_insert_with(|| ImageState {
+                layout: current(image, level),
+                access: AccessState::UNUSED,
+            });
+
+        let old = std::mem::replace(&mut state.layout, access.layout);
+        if old != access.layout {
+            state.access = AccessState::transitioned(access.access);
+        } else {
+            state.access.barrier(src, access.access);
+        }
+        old
+    }
+
+    /// Layout each mip level used by the recording is left in.
+    pub(crate) fn layouts(&self) -> impl Iterator<Item = ((vk::Image, u32), vk::ImageLayout)> + '_ {
+        self.images.iter().map(|(key, state)| (*key, state.layout))
+    }
+}
+
+impl AccessState {
+    const UNUSED: Self = Self {
+        write: Access::NONE,
+        visible: Access::NONE,
+        reads: vk::PipelineStageFlags2KHR::NONE,
+    };
+
+    /// Right after a layout transition, which is a write made visible to `dst`.
+    fn transitioned(dst: Access) -> Self {
+        if dst.is_write() {
+            Self {
+                write: dst,
+                ..Self::UNUSED
+            }
+        } else {
+            Self {
+                write: Access::new(dst.stage, vk::AccessFlags2KHR::MEMORY_WRITE),
+                visible: dst,
+                reads: dst.stage,
+            }
+        }
+    }
+
+    /// What must be waited for before `dst`, which is then remembered.
+    /// Nothing if its stages are empty.
+    fn access(&mut self, dst: Access) -> Access {
+        let mut src = Access::NONE;
+        // Read or write after write
+        if !self.write.access.is_empty() && !self.visible.covers(&dst) {
+            src = src | self.write;
+        }
+        // Write after read, only the execution has to be ordered
+        if dst.is_write() {
+            src.stage |= self.reads;
+        }
+
+        if !src.stage.is_empty() {
+            self.visible = self.visible | dst;
+        }
+
+        if dst.is_write() {
+            *self = Self {
+                write: dst,
+                ..Self::UNUSED
+            };
+        } else {
+            self.reads |= dst.stage;
+        }
+        src
+    }
+
+    /// Reads since the last write, to order their execution.
+    fn reads_only(&self) -> Access {
+        Access::new(self.reads, vk::AccessFlags2KHR::NONE)
+    }
+
+    fn barrier(&mut self, src: Access, dst: Access) {
+        if src.covers(&self.write) {
+            self.visible = self.visible | dst;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    fn buffer(raw: u64) -> vk::Buffer {
+        vk::Buffer::from_raw(raw)
+    }
+
+    fn access(handle: vk::Buffer, access: Access) -> BufferAccess {
+        BufferAccess {
+            handle,
+            offset: 0,
+            size: 64,
+            access,
+        }
+    }
+
+    fn image_access(level: u32, layout: vk::ImageLayout, access: Access) -> ImageAccess {
+        ImageAccess {
+            image: vk::Image::from_raw(1),
+            range: vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(level)
+                .level_count(1)
+                .layer_count(1)
+                .build(),
+            layout,
+            access,
+        }
+    }
+
+    /// Levels the recording hasn't used yet are all in `GENERAL`.
+    fn general(_: vk::Image, _: u32) -> vk::ImageLayout {
+        vk::ImageLayout::GENERAL
+    }
+
+    /// (src, dst, old layout, new layout) of each barrier needed before `accesses`.
+    fn image_barriers(
+        tracker: &mut HazardTracker,
+        accesses: &[ImageAccess],
+    ) -> Vec<(Access, Access, vk::ImageLayout, vk::ImageLayout)> {
+        tracker
+            .access_images(accesses, general)
+            .iter()
+            .map(|b| (b.src, b.dst, b.old_layout, b.new_layout))
+            .collect()
+    }
+
+    /// (src, dst) of each barrier needed before `accesses`.
+    fn barriers(tracker: &mut HazardTracker, accesses: &[BufferAccess]) -> Vec<(Access, Access)> {
+        tracker
+            .access(accesses)
+            .iter()
+            .map(|b| (b.src, b.dst))
+            .collect()
+    }
+
+    #[test]
+    fn first_access_needs_no_barrier() {
+        let mut tracker = HazardTracker::default();
+        assert!(barriers(&mut tracker, &[access(buffer(1), Access::TRANSFER_WRITE)]).is_empty());
+        assert!(barriers(&mut tracker, &[access(buffer(2), Access::TRANSFER_READ)]).is_empty());
+    }
+
+    #[test]
+    fn read_after_write() {
+        let mut tracker = HazardTracker::default();
+        let b = buffer(1);
+        tracker.access(&[access(b, Access::TRANSFER_WRITE)]);
+
+        assert_eq!(
+            barriers(&mut tracker, &[access(b, Access::COMPUTE_SHADER_READ)]),
+            vec![(Access::TRANSFER_WRITE, Access::COMPUTE_SHADER_READ)]
+        );
+        // Already visible to compute shader reads
+        assert!(barriers(&mut tracker, &[access(b, Access::COMPUTE_SHADER_READ)]).is_empty());
+        // But not to transfer reads
+        assert_eq!(
+            barriers(&mut tracker, &[access(b, Access::TRANSFER_READ)]),
+            vec![(Access::TRANSFER_WRITE, Access::TRANSFER_READ)]
+        );
+    }
+
+    #[test]
+    fn write_after_write() {
+        let mut tracker = HazardTracker::default();
+        let b = buffer(1);
+        tracker.access(&[access(b, Access::TRANSFER_WRITE)]);
+
+        assert_eq!(
+            barriers(&mut tracker, &[access(b, Access::COMPUTE_SHADER_WRITE)]),
+            vec![(Access::TRANSFER_WRITE, Access::COMPUTE_SHADER_WRITE)]
+        );
+        assert_eq!(
+            barriers(&mut tracker, &[access(b, Access::TRANSFER_WRITE)]),
+            vec![(Access::COMPUTE_SHADER_WRITE, Access::TRANSFER_WRITE)]
+        );
+    }
+
+    #[test]
+    fn write_after_read_only_orders_execution() {
+        let mut tracker = HazardTracker::default();
+        let b = buffer(1);
+        tracker.access(&[access(b, Access::TRANSFER_READ)]);
+        tracker.access(&[access(b, Access::COMPUTE_SHADER_READ)]);
+
+        let expected_src = Access::new(
+            vk::PipelineStageFlags2KHR::TRANSFER | vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+            vk::AccessFlags2KHR::NONE,
+        );
+        assert_eq!(
+            barriers(&mut tracker, &[access(b, Access::TRANSFER_WRITE)]),
+            vec![(expected_src, Access::TRANSFER_WRITE)]
+        );
+    }
+
+    #[test]
+    fn read_after_read_needs_no_barrier() {
+        let mut tracker = HazardTracker::default();
+        let b = buffer(1);
+        tracker.access(&[access(b, Access::TRANSFER_READ)]);
+        assert!(barriers(&mut tracker, &[access(b, Access::COMPUTE_SHADER_READ)]).is_empty());
+    }
+
+    #[test]
+    fn buffers_are_independent() {
+        let mut tracker = HazardTracker::default();
+        tracker.access(&[access(buffer(1), Access::TRANSFER_WRITE)]);
+        assert!(barriers(&mut tracker, &[access(buffer(2), Access::TRANSFER_READ)]).is_empty());
+    }
+
+    #[test]
+    fn accesses_of_one_command_are_merged() {
+        let b = buffer(1);
+        let mut read = access(b, Access::TRANSFER_READ);
+        read.offset = 64;
+        let mut write = access(b, Access::TRANSFER_WRITE);
+        write.offset = 0;
+        write.size = 16;
+
+        let mut merged = read;
+        merged.merge(&write);
+        assert_eq!((merged.offset, merged.size), (0, 128));
+        assert_eq!(
+            merged.access,
+            Access::TRANSFER_READ | Access::TRANSFER_WRITE
+        );
+
+        // A copy within the buffer doesn't depend on itself, the next one does
+        let mut tracker = HazardTracker::default();
+        assert!(barriers(&mut tracker, &[read, write]).is_empty());
+        let next = barriers(&mut tracker, &[read, write]);
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].1, Access::TRANSFER_READ | Access::TRANSFER_WRITE);
+    }
+
+    #[test]
+    fn explicit_buffer_barrier_covers_the_write() {
+        let mut tracker = HazardTracker::default();
+        let b = buffer(1);
+        tracker.access(&[access(b, Access::TRANSFER_WRITE)]);
+        tracker.barrier(Some(b), Access::TRANSFER_WRITE, Access::COMPUTE_SHADER_READ);
+
+        assert!(barriers(&mut tracker, &[access(b, Access::COMPUTE_SHADER_READ)]).is_empty());
+    }
+
+    #[test]
+    fn explicit_global_barrier_covers_every_buffer() {
+        let mut tracker = HazardTracker::default();
+        tracker.access(&[access(buffer(1), Access::TRANSFER_WRITE)]);
+        tracker.access(&[access(buffer(2), Access::COMPUTE_SHADER_WRITE)]);
+        tracker.barrier(None, Access::ALL, Access::ALL);
+
+        assert!(barriers(&mut tracker, &[access(buffer(1), Access::HOST_READ)]).is_empty());
+        assert!(barriers(
+            &mut tracker,
+            &[access(buffer(2), Access::COMPUTE_SHADER_READ)]
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn explicit_barrier_of_another_write_is_ignored() {
+        let mut tracker = HazardTracker::default();
+        let b = buffer(1);
+        tracker.access(&[access(b, Access::TRANSFER_WRITE)]);
+        // Doesn't cover the transfer stage
+        tracker.barrier(
+            Some(b),
+            Access::COMPUTE_SHADER_WRITE,
+            Access::COMPUTE_SHADER_READ,
+        );
+        // Another buffer
+        tracker.barrier(Some(buffer(2)), Access::ALL, Access::ALL);
+
+        assert_eq!(
+            barriers(&mut tracker, &[access(b, Access::COMPUTE_SHADER_READ)]),
+            vec![(Access::TRANSFER_WRITE, Access::COMPUTE_SHADER_READ)]
+        );
+    }
+
+    #[test]
+    fn first_use_of_an_image_transitions_it() {
+        use vk::ImageLayout as L;
+
+        let mut tracker = HazardTracker::default();
+        let write = image_access(0, L::TRANSFER_DST_OPTIMAL, Access::TRANSFER_WRITE);
+        assert_eq!(
+            image_barriers(&mut tracker, &[write]),
+            vec![(
+                Access::NONE,
+                Access::TRANSFER_WRITE,
+                L::GENERAL,
+                L::TRANSFER_DST_OPTIMAL
+            )]
+        );
+        // Already in the right layout, only the write has to be waited for
+        assert_eq!(
+            image_barriers(&mut tracker, &[write]),
+            vec![(
+                Access::TRANSFER_WRITE,
+                Access::TRANSFER_WRITE,
+                L::TRANSFER_DST_OPTIMAL,
+                L::TRANSFER_DST_OPTIMAL
+            )]
+        );
+        // Already in its current layout
+        let read = image_access(1, L::GENERAL, Access::TRANSFER_READ);
+        assert!(image_barriers(&mut tracker, &[read]).is_empty());
+    }
+
+    #[test]
+    fn layout_change_waits_for_prior_accesses() {
+        use vk::ImageLayout as L;
+
+        let mut tracker = HazardTracker::default();
+        tracker.access_images(
+            &[image_access(
+                0,
+                L::TRANSFER_DST_OPTIMAL,
+                Access::TRANSFER_WRITE,
+            )],
+            general,
+        );
+        let read = image_access(0, L::TRANSFER_SRC_OPTIMAL, Access::TRANSFER_READ);
+        assert_eq!(
+            image_barriers(&mut tracker, &[read]),
+            vec![(
+                Access::TRANSFER_WRITE,
+                Access::TRANSFER_READ,
+                L::TRANSFER_DST_OPTIMAL,
+                L::TRANSFER_SRC_OPTIMAL
+            )]
+        );
+        // The transition was made visible to transfer reads
+        assert!(image_barriers(&mut tracker, &[read]).is_empty());
+
+        // Moving it out of that layout has to wait for those reads
+        let shader = image_access(0, L::GENERAL, Access::COMPUTE_SHADER_READ);
+        let barriers = image_barriers(&mut tracker, &[shader]);
+        assert_eq!(barriers.len(), 1);
+        assert!(barriers[0]
+            .0
+            .stage
+            .contains(vk::PipelineStageFlags2KHR::TRANSFER));
+        assert_eq!(barriers[0].2, L::TRANSFER_SRC_OPTIMAL);
+    }
+
+    #[test]
+    fn mip_levels_are_independent() {
+        use vk::ImageLayout as L;
+
+        let mut tracker = HazardTracker::default();
+        tracker.access_images(
+            &[image_access(0, L::GENERAL, Access::COMPUTE_SHADER_WRITE)],
+            general,
+        );
+        assert!(image_barriers(
+            &mut tracker,
+            &[image_access(1, L::GENERAL, Access::COMPUTE_SHADER_READ)]
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn explicit_image_barrier_transitions_the_level() {
+        use vk::ImageLayout as L;
+
+        let mut tracker = HazardTracker::default();
+        let write = image_access(0, L::GENERAL, Access::COMPUTE_SHADER_WRITE);
+        tracker.access_images(&[write], general);
+
+        let read = image_access(0, L::TRANSFER_SRC_OPTIMAL, Access::TRANSFER_READ);
+        assert_eq!(
+            tracker.image_barrier(&read, Access::COMPUTE_SHADER_WRITE, general),
+            L::GENERAL
+        );
+        assert!(image_barriers(&mut tracker, &[read]).is_empty());
+
+        // A global barrier also covers images
+        tracker.access_images(
+            &[image_access(
+                0,
+                L::TRANSFER_SRC_OPTIMAL,
+                Access::TRANSFER_WRITE,
+            )],
+            general,
+        );
+        tracker.barrier(None, Access::ALL, Access::ALL);
+        assert!(image_barriers(&mut tracker, &[read]).is_empty());
+    }
+
+    #[test]
+    fn layouts_left_by_the_recording() {
+        use vk::ImageLayout as L;
+
+        let mut tracker = HazardTracker::default();
+        tracker.access_images(
+            &[
+                image_access(0, L::TRANSFER_DST_OPTIMAL, Access::TRANSFER_WRITE),
+                image_access(2, L::TRANSFER_SRC_OPTIMAL, Access::TRANSFER_READ),
+            ],
+            general,
+        );
+        tracker.access_images(
+            &[image_access(0, L::GENERAL, Access::COMPUTE_SHADER_READ)],
+            general,
+        );
+
+        let mut layouts: Vec<_> = tracker
+            .layouts()
+            .map(|((_, level), layout)| (level, layout))
+            .collect();
+        layouts.sort_by_key(|(level, _)| *level);
+        assert_eq!(layouts, vec![(0, L::GENERAL), (2, L::TRANSFER_SRC_OPTIMAL)]);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{errors::Result, mem::ImageLayouts, setup::QueueKind, tasks::Resource, VulkanApp};
+use ash::vk;
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Queue that last used an `EXCLUSIVE` resource, `None` until it's first used.
+///
+/// Using the resource on a queue of another family first transfers its ownership,
+/// see [`VulkanApp::acquire_ownership`].
+#[derive(Clone, Default)]
+pub struct QueueOwner(Arc<Mutex<Option<QueueKind>>>);
+
+/// An `EXCLUSIVE` resource used by a submission, with what transferring its ownership needs.
+#[derive(Clone)]
+pub(crate) struct Owned {
+    pub(crate) owner: QueueOwner,
+    pub(crate) resource: Transferred,
+    /// Kept alive until the transfer is done.
+    pub(crate) retained: Resource,
+}
+
+#[derive(Clone)]
+pub(crate) enum Transferred {
+    /// The whole buffer, slices included.
+    Buffer(vk::Buffer),
+    /// Every mip level and array layer, the levels keep their current layout.
+    Image {
+        handle: vk::Image,
+        aspect: vk::ImageAspectFlags,
+        layers: u32,
+        layouts: ImageLayouts,
+    },
+}
+
+/// Add `owned` to the resources of a submission, unless it's already there.
+pub(crate) fn push_owned(owned: &mut Vec<Owned>, resource: Option<Owned>) {
+    if let Some(resource) = resource {
+        if !owned
+            .iter()
+            .any(|o| Arc::ptr_eq(&o.owner.0, &resource.owner.0))
+        {
+            owned.push(resource);
+        }
+    }
+}
+
+impl Owned {
+    /// Barriers transferring the resource from the `src` family to `dst`, without access masks.
+    ///
+    /// Mip levels with an undefined content are left out, the whole image if none is defined.
+    fn transfer_barriers(
+        &self,
+        src: u32,
+        dst: u32,
+    ) -> (Vec<vk::BufferMemoryBarrier>, Vec<vk::ImageMemoryBarrier>) {
+        match &self.resource {
+            Transferred::Buffer(handle) => {
+                let barrier = vk::BufferMemoryBarrier::builder()
+                    .src_queue_family_index(src)
+                    .dst_queue_family_index(dst)
+                    .buffer(*handle)
+                    .offset(0)
+                    .size(vk::WHOLE_SIZE)
+                    .build();
+                (vec![barrier], Vec::new())
+            }
+            Transferred::Image {
+                handle,
+                aspect,
+                layers,
+                layouts,
+            } => {
+                let images = layouts
+                    .lock()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, layout)| **layout != vk::ImageLayout::UNDEFINED)
+                    .map(|(level, layout)| {
+                        vk::ImageMemoryBarrier::builder()
+                            .old_layout(*layout)
+                            .new_layout(*layout)
+                            .src_queue_family_index(src)
+                            .dst_queue_family_index(dst)
+                            .image(*handle)
+                            .subresource_range(
+                                vk::ImageSubresourceRange::builder()
+                                    .aspect_mask(*aspect)
+                                    .base_mip_level(level as _)
+                                    .level_count(1)
+                                    .base_array_layer(0)
+                                    .layer_count(*layers)
+                                    .build(),
+                            )
+                            .build()
+                    })
+                    .collect();
+                (Vec::new(), images)
+            }
+        }
+    }
+}
+
+impl VulkanApp {
+    /// Make `queue` the owner of an `EXCLUSIVE` resource before submitting commands using it.
+    ///
+    /// If it was last used on another queue family, its ownership is released on that queue
+    /// and acquired on `queue`, which orders the transfer before what is submitted to it next.
+    pub(crate) fn acquire_ownership(&self, owned: &Owned, queue: QueueKind) -> Result<()> {
+        let mut owner = owned.owner.0.lock();
+        let dst = self.queues.get(queue).family;
+        let previous = match *owner {
+            Some(previous) if self.queues.get(previous).family != dst => previous,
+            _ => {
+                *owner = Some(queue);
+                return Ok(());
+            }
+        };
+        let src = self.queues.get(previous).family;
+
+        let (mut buffers, mut images) = owned.transfer_barriers(src, dst);
+        if buffers.is_empty() && images.is_empty() {
+            *owner = Some(queue);
+            return Ok(());
+        }
+
+        // Release, once everything submitted before on the previous queue is done with it
+        let mut release = unsafe {
+            self.record_commands(previous, |device, cmd| {
+                for barrier in &mut buffers {
+                    barrier.src_access_mask = vk::AccessFlags::MEMORY_WRITE;
+                }
+                for barrier in &mut images {
+                    barrier.src_access_mask = vk::AccessFlags::MEMORY_WRITE;
+                }
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::ALL_COMMANDS,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &buffers,
+                    &images,
+                );
+                Ok(())
+            })?
+        };
+        release.retain(Arc::clone(&owned.retained));
+        let release = release.submit()?;
+
+        // Acquire, before everything submitted after on the new queue
+        let mut acquire = unsafe {
+            self.record_commands(queue, |device, cmd| {
+                for barrier in &mut buffers {
+                    barrier.src_access_mask = vk::AccessFlags::empty();
+                    barrier.dst_access_mask =
+                        vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE;
+                }
+                for barrier in &mut images {
+                    barrier.src_access_mask = vk::AccessFlags::empty();
+                    barrier.dst_access_mask =
+                        vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE;
+                }
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::ALL_COMMANDS,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &buffers,
+                    &images,
+                );
+                Ok(())
+            })?
+        }
+        .after(&[&release]);
+        acquire.retain(Arc::clone(&owned.retained));
+        acquire.submit()?;
+
+        *owner = Some(queue);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    fn owned(resource: Transferred) -> Owned {
+        Owned {
+            owner: QueueOwner::default(),
+            resource,
+            retained: Arc::new(()),
+        }
+    }
+
+    #[test]
+    fn resources_are_owned_once() {
+        let buffer = owned(Transferred::Buffer(vk::Buffer::from_raw(1)));
+        let mut list = Vec::new();
+        push_owned(&mut list, Some(buffer.clone()));
+        push_owned(&mut list, Some(buffer));
+        push_owned(&mut list, None);
+        push_owned(
+            &mut list,
+            Some(owned(Transferred::Buffer(vk::Buffer::from_raw(2)))),
+        );
+        assert_eq!(list.len(), 2);
+    }
+
+    #[test]
+    fn undefined_levels_arent_transferred() {
+        use vk::ImageLayout as L;
+
+        let layouts = Arc::new(Mutex::new(vec![
+            L::GENERAL,
+            L::UNDEFINED,
+            L::TRANSFER_SRC_OPTIMAL,
+        ]));
+        let image = owned(Transferred::Image {
+            handle: vk::Image::from_raw(1),
+            aspect: vk::ImageAspectFlags::COLOR,
+            layers: 2,
+            layouts: Arc::clone(&layouts),
+        });
+
+        let (buffers, images) = image.transfer_barriers(0, 1);
+        assert!(buffers.is_empty());
+        let levels: Vec<_> = images
+            .iter()
+            .map(|b| {
+                assert_eq!(b.old_layout, b.new_layout);
+                assert_eq!((b.src_queue_family_index, b.dst_queue_family_index), (0, 1));
+                assert_eq!(b.subresource_range.layer_count, 2);
+                (b.subresource_range.base_mip_level, b.old_layout)
+            })
+            .collect();
+        assert_eq!(levels, vec![(0, L::GENERAL), (2, L::TRANSFER_SRC_OPTIMAL)]);
+
+        *layouts.lock() = vec![L::UNDEFINED; 3];
+        assert!(image.transfer_barriers(0, 1).1.is_empty());
+    }
+}
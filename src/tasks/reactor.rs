@@ -0,0 +1,416 @@
+use crate::{errors::Result, tasks::Resource};
+use ash::vk;
+use log::error;
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::Waker,
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+/// Timeline semaphore owned by a queue, every submission to that queue signals the next value.
+pub(crate) struct Timeline {
+    pub(crate) semaphore: vk::Semaphore,
+    /// Last value handed to a submission, only written while the queue is locked.
+    pub(crate) last_submitted: AtomicU64,
+    /// Last value the reactor observed on the semaphore.
+    completed: AtomicU64,
+    waiters: Mutex<Vec<(u64, Waker)>>,
+    /// Resources used by each submission still in flight, in submission order.
+    retained: Mutex<VecDeque<(u64, Vec<Resource>)>>,
+}
+
+unsafe fn create_timeline_semaphore(device: &ash::Device) -> Result<vk::Semaphore> {
+    Ok(device.create_semaphore(
+        &vk::SemaphoreCreateInfo::builder().push_next(
+            &mut vk::SemaphoreTypeCreateInfo::builder()
+                .semaphore_type(vk::SemaphoreType::TIMELINE)
+                .initial_value(0),
+        ),
+        None,
+    )?)
+}
+
+impl Timeline {
+    pub(crate) unsafe fn new(device: &ash::Device) -> Result<Self> {
+        Ok(Self {
+            semaphore: create_timeline_semaphore(device)?,
+            last_submitted: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            waiters: Mutex::new(Vec::new()),
+            retained: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn is_reached(&self, value: u64) -> bool {
+        self.completed.load(Ordering::Acquire) >= value
+    }
+
+    /// Remember to wake `waker` once `value` is reached.
+    /// Returns whether the reactor needs to be interrupted to take this value into account.
+    fn add_waiter(&self, value: u64, waker: &Waker) -> bool {
+        let mut waiters = self.waiters.lock();
+
+        if waiters
+            .iter()
+            .any(|(v, w)| *v == value && w.will_wake(waker))
+        {
+            return false;
+        }
+
+        let need_interrupt = waiters.iter().all(|(v, _)| *v > value);
+        waiters.push((value, waker.clone()));
+        need_interrupt
+    }
+
+    /// Keep `resources` alive until `value` is reached.
+    /// Returns whether the reactor needs to be interrupted to take this value into account.
+    fn retain_until(&self, value: u64, resources: Vec<Resource>) -> bool {
+        if resources.is_empty() {
+            return false;
+        }
+
+        let mut retained = self.retained.lock();
+        // Checked under the lock so that it can't be missed by `complete_up_to`
+        if self.is_reached(value) {
+            return false;
+        }
+
+        // Usually pushed in increasing order, except for resources retained after their submission
+        let index = retained.partition_point(|(v, _)| *v <= value);
+        retained.insert(index, (value, resources));
+        index == 0 && self.waiters.lock().iter().all(|(v, _)| *v > value)
+    }
+
+    /// Drop every retained resource, only once the device is idle.
+    pub(crate) fn release_all(&self) {
+        self.retained.lock().clear();
+    }
+
+    /// Smallest value awaited by a future or releasing resources.
+    fn min_waiting(&self) -> Option<u64> {
+        let retained = self.retained.lock().front().map(|(v, _)| *v);
+        let waiting = self.waiters.lock().iter().map(|(v, _)| *v).min();
+        match (retained, waiting) {
+            (Some(r), Some(w)) => Some(r.min(w)),
+            (r, w) => r.or(w),
+        }
+    }
+
+    fn complete_up_to(&self, value: u64) {
+        self.completed.fetch_max(value, Ordering::AcqRel);
+
+        let ready = {
+            let mut waiters = self.waiters.lock();
+            let (ready, pending) = waiters.drain(..).partition(|(v, _)| *v <= value);
+            *waiters = pending;
+            ready
+        };
+
+        let released = {
+            let mut retained = self.retained.lock();
+            let done = retained.iter().take_while(|(v, _)| *v <= value).count();
+            retained.drain(..done).collect::<Vec<_>>()
+        };
+        // Destroyed outside of the lock, before waking so that futures see them gone
+        drop(released);
+
+        wake_all(ready);
+    }
+
+    fn wake_everyone(&self) {
+        let wakers = std::mem::take(&mut *self.waiters.lock());
+        wake_all(wakers);
+    }
+}
+
+fn wake_all(wakers: Vec<(u64, Waker)>) {
+    for (_, waker) in wakers {
+        waker.wake();
+    }
+}
+
+pub(crate) struct ReactorShared {
+    device: ash::Device,
+    /// Host signaled timeline used to kick the reactor out of its wait.
+    interrupt: vk::Semaphore,
+    timelines: Vec<Arc<Timeline>>,
+    state: Mutex<ReactorState>,
+}
+
+struct ReactorState {
+    running: bool,
+    interrupt_value: u64,
+    error: Option<vk::Result>,
+    /// Futures with a timeout, woken once it expires even if their value isn't reached.
+    /// Keyed by id so that futures completing or dropped before it remove theirs.
+    deadlines: Vec<(u64, Instant, Waker)>,
+    next_deadline_id: u64,
+}
+
+/// Background thread watching the timeline semaphore of every queue.
+///
+/// It blocks in a single `vkWaitSemaphores` for the smallest value awaited on each queue
+/// and only wakes the futures whose value has been reached.
+pub(crate) struct TimelineReactor {
+    shared: Arc<ReactorShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TimelineReactor {
+    pub(crate) fn new(device: ash::Device, timelines: Vec<Arc<Timeline>>) -> Result<Self> {
+        let interrupt = unsafe { create_timeline_semaphore(&device)? };
+
+        let shared = Arc::new(ReactorShared {
+            device,
+            interrupt,
+            timelines,
+            state: Mutex::new(ReactorState {
+                running: true,
+                interrupt_value: 0,
+                error: None,
+                deadlines: Vec::new(),
+                next_deadline_id: 0,
+            }),
+        });
+
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("vk-async-reactor".into())
+                .spawn(move || shared.run())
+                .expect("Failed to spawn the timeline reactor thread")
+        };
+
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn shared(&self) -> &Arc<ReactorShared> {
+        &self.shared
+    }
+
+    /// Stop the reactor thread and wake every future still waiting.
+    ///
+    /// The device should be idle, values that are not reached by then will never be.
+    pub(crate) fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            {
+                let mut state = self.shared.state.lock();
+                state.running = false;
+                self.shared.signal_interrupt(&mut state);
+            }
+
+            if thread.join().is_err() {
+                error!("The timeline reactor thread panicked");
+            }
+
+            if let Err(e) = self.shared.refresh() {
+                error!("Failed to read back timeline semaphores: {}", e);
+            }
+            for timeline in &self.shared.timelines {
+                timeline.wake_everyone();
+            }
+
+            unsafe {
+                self.shared
+                    .device
+                    .destroy_semaphore(self.shared.interrupt, None);
+            }
+        }
+    }
+}
+
+impl Drop for TimelineReactor {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Why a value can't be awaited anymore.
+pub(crate) enum ReactorFailure {
+    Stopped,
+    Error(vk::Result),
+}
+
+impl ReactorShared {
+    /// Register interest in `value` of `timeline`.
+    /// Returns an error if the value will never be observed.
+    pub(crate) fn wait_for(
+        &self,
+        timeline: &Timeline,
+        value: u64,
+        waker: &Waker,
+    ) -> std::result::Result<(), ReactorFailure> {
+        {
+            let state = self.state.lock();
+            if let Some(e) = state.error {
+                return Err(ReactorFailure::Error(e));
+            }
+            if !state.running {
+                return Err(ReactorFailure::Stopped);
+            }
+        }
+
+        if timeline.add_waiter(value, waker) {
+            let mut state = self.state.lock();
+            if state.running {
+                self.signal_interrupt(&mut state);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The error that stopped the reactor, nothing can be awaited anymore once there is one.
+    pub(crate) fn error(&self) -> Option<vk::Result> {
+        self.state.lock().error
+    }
+
+    /// Wake `waker` at `deadline`, even if what it waits for isn't reached.
+    ///
+    /// `id` is what a previous call returned, its entry is updated if it hasn't expired yet.
+    /// The returned id must be given to [`cancel_deadline`](Self::cancel_deadline) once
+    /// the waker isn't interested anymore.
+    pub(crate) fn wake_at(&self, id: Option<u64>, deadline: Instant, waker: &Waker) -> u64 {
+        let mut state = self.state.lock();
+        if let Some(entry) = id.and_then(|id| state.deadlines.iter_mut().find(|e| e.0 == id)) {
+            if !entry.2.will_wake(waker) {
+                entry.2 = waker.clone();
+            }
+            return entry.0;
+        }
+
+        let id = state.next_deadline_id;
+        state.next_deadline_id += 1;
+        let need_interrupt = state.deadlines.iter().all(|(_, d, _)| *d > deadline);
+        state.deadlines.push((id, deadline, waker.clone()));
+        if need_interrupt && state.running {
+            self.signal_interrupt(&mut state);
+        }
+        id
+    }
+
+    /// Forget a deadline registered with [`wake_at`](Self::wake_at), if it hasn't expired yet.
+    pub(crate) fn cancel_deadline(&self, id: u64) {
+        self.state.lock().deadlines.retain(|e| e.0 != id);
+    }
+
+    /// Keep `resources` alive until `value` of `timeline` is reached, even if nothing awaits it.
+    pub(crate) fn retain_until(&self, timeline: &Timeline, value: u64, resources: Vec<Resource>) {
+        if timeline.retain_until(value, resources) {
+            let mut state = self.state.lock();
+            if state.running {
+                self.signal_interrupt(&mut state);
+            }
+        }
+    }
+
+    fn signal_interrupt(&self, state: &mut ReactorState) {
+        state.interrupt_value += 1;
+
+        let res = unsafe {
+            self.device.signal_semaphore(
+                &vk::SemaphoreSignalInfo::builder()
+                    .semaphore(self.interrupt)
+                    .value(state.interrupt_value),
+            )
+        };
+
+        if let Err(e) = res {
+            error!("Failed to interrupt the timeline reactor: {}", e);
+        }
+    }
+
+    fn run(&self) {
+        loop {
+            let (interrupt_value, timeout) = {
+                let state = self.state.lock();
+                if !state.running {
+                    return;
+                }
+                let now = Instant::now();
+                let timeout = state
+                    .deadlines
+                    .iter()
+                    .map(|(_, d, _)| d.saturating_duration_since(now).as_nanos() as u64)
+                    .min()
+                    .unwrap_or(u64::MAX);
+                (state.interrupt_value, timeout)
+            };
+
+            let mut semaphores = vec![self.interrupt];
+            let mut values = vec![interrupt_value + 1];
+            for timeline in &self.timelines {
+                if let Some(value) = timeline.min_waiting() {
+                    semaphores.push(timeline.semaphore);
+                    values.push(value);
+                }
+            }
+
+            let res = unsafe {
+                self.device.wait_semaphores(
+                    &vk::SemaphoreWaitInfo::builder()
+                        .flags(vk::SemaphoreWaitFlags::ANY)
+                        .semaphores(&semaphores)
+                        .values(&values),
+                    timeout,
+                )
+            };
+
+            match res.and_then(|_| self.refresh()) {
+                Ok(_) | Err(vk::Result::TIMEOUT) => {}
+                Err(e) => {
+                    self.fail(e);
+                    return;
+                }
+            }
+            self.wake_expired();
+        }
+    }
+
+    fn wake_expired(&self) {
+        let now = Instant::now();
+        let expired = {
+            let mut state = self.state.lock();
+            let (expired, pending) = state.deadlines.drain(..).partition(|(_, d, _)| *d <= now);
+            state.deadlines = pending;
+            expired
+        };
+
+        for (_, _, waker) in expired {
+            waker.wake();
+        }
+    }
+
+    /// Read back every timeline and wake whoever can be.
+    fn refresh(&self) -> ash::prelude::VkResult<()> {
+        for timeline in &self.timelines {
+            let value = unsafe {
+                self.device
+                    .get_semaphore_counter_value(timeline.semaphore)?
+            };
+            timeline.complete_up_to(value);
+        }
+        Ok(())
+    }
+
+    /// Make every future waiting, or about to, resolve with this error.
+    pub(crate) fn fail(&self, e: vk::Result) {
+        error!("Failed to wait for timeline semaphores: {}", e);
+
+        self.state.lock().error = Some(e);
+        for timeline in &self.timelines {
+            timeline.wake_everyone();
+        }
+    }
+}
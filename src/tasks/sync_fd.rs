@@ -0,0 +1,134 @@
+use crate::{
+    errors::{Result, VulkanError},
+    tasks::{RecordedCommands, Resource, TimelineFuture},
+};
+use ash::vk;
+use std::{
+    os::unix::io::{FromRawFd, OwnedFd},
+    sync::Arc,
+};
+
+impl RecordedCommands<'_> {
+    /// Submit the commands and export the fence they signal as a sync_fd.
+    ///
+    /// The file descriptor becomes readable once the commands are executed, so it can be
+    /// registered with any I/O reactor (epoll, mio...) instead of a thread waiting on the GPU.
+    /// It's `None` if the submission is already done.
+    ///
+    /// Exporting can still fail once the commands are submitted, the future is returned
+    /// regardless so that the submission can be awaited anyway.
+    ///
+    /// Fails with [`VulkanError::MissingExtension`] if the device doesn't support
+    /// `VK_KHR_external_fence_fd`, see [`PhysicalDeviceInfo::supports_sync_fd`](crate::setup::PhysicalDeviceInfo::supports_sync_fd).
+    pub fn submit_with_sync_fd(mut self) -> Result<(TimelineFuture, Result<Option<OwnedFd>>)> {
+        let app = self.app;
+        let external_fence_fd = app
+            .external_fence_fd
+            .as_ref()
+            .ok_or(VulkanError::MissingExtension("VK_KHR_external_fence_fd"))?;
+
+        let fence = Arc::new(app.fences.acquire()?);
+        // Only back in the pool once signaled, exporting resets it but the submission still owns it
+        self.retain(Arc::clone(&fence) as Resource);
+        let future = self.submit_signaling(fence.fence)?;
+        fence.set_signal_pending(true);
+
+        let fd = unsafe {
+            external_fence_fd.get_fence_fd(
+                &vk::FenceGetFdInfoKHR::builder()
+                    .fence(fence.fence)
+                    .handle_type(vk::ExternalFenceHandleTypeFlags::SYNC_FD),
+            )
+        };
+        let fd = match fd {
+            Ok(fd) => fd,
+            Err(e) => return Ok((future, Err(e.into()))),
+        };
+
+        // The signal operation now belongs to the fd, exporting reset the fence
+        fence.set_signal_pending(false);
+
+        // -1 means the fence was already signaled
+        let fd = (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd) });
+        Ok((future, Ok(fd)))
+    }
+}
+
+#[cfg(feature = "sync-fd-tokio")]
+pub use self::tokio_fd::SyncFdFuture;
+
+#[cfg(feature = "sync-fd-tokio")]
+mod tokio_fd {
+    use crate::{
+        errors::Result,
+        tasks::{RecordedCommands, TimelineFuture},
+    };
+    use log::warn;
+    use std::{
+        future::Future,
+        os::unix::io::OwnedFd,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{unix::AsyncFd, Interest};
+
+    /// Resolves once a submission is executed, woken by tokio's I/O reactor through a sync_fd.
+    ///
+    /// Unlike [`TimelineFuture`] no thread waits on the GPU, but the resources of the submission
+    /// are still released by the reactor of the app once its timeline value is reached.
+    /// If the fence couldn't be exported or registered, it falls back to the timeline future.
+    pub struct SyncFdFuture {
+        inner: TimelineFuture,
+        /// `None` once ready, or if the timeline future is awaited instead.
+        fd: Option<AsyncFd<OwnedFd>>,
+    }
+
+    impl SyncFdFuture {
+        /// The timeline future of the same submission.
+        pub fn timeline_future(&self) -> &TimelineFuture {
+            &self.inner
+        }
+    }
+
+    impl RecordedCommands<'_> {
+        /// Same as [`submit_with_sync_fd`](Self::submit_with_sync_fd) but the file descriptor
+        /// is registered with tokio, which must be running.
+        pub fn submit_with_tokio(self) -> Result<SyncFdFuture> {
+            let (inner, fd) = self.submit_with_sync_fd()?;
+            let fd = fd.and_then(|fd| {
+                fd.map(|fd| AsyncFd::with_interest(fd, Interest::READABLE))
+                    .transpose()
+                    .map_err(Into::into)
+            });
+
+            // Already submitted, only the way it's awaited changes
+            let fd = fd.unwrap_or_else(|e| {
+                warn!("Awaiting the timeline instead of a sync_fd: {}", e);
+                None
+            });
+            Ok(SyncFdFuture { inner, fd })
+        }
+    }
+
+    impl Future for SyncFdFuture {
+        type Output = Result<()>;
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+            let fd = match &self.fd {
+                Some(fd) => fd,
+                // Either already done, or the fd couldn't be used
+                None => return Pin::new(&mut self.inner).poll(ctx),
+            };
+
+            match fd.poll_read_ready(ctx).map_ok(|_guard| ()) {
+                Poll::Ready(Ok(())) => {
+                    // The fd is closed right away, it's only signaled once
+                    self.fd = None;
+                    Poll::Ready(Ok(()))
+                }
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+}
@@ -1 +1,73 @@
+use std::mem::MaybeUninit;
 
+/// View plain data as raw bytes.
+#[inline]
+pub(crate) fn as_bytes<D: Sized + Copy>(data: &[D]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) }
+}
+
+/// View possibly uninitialized plain data as raw bytes to fill.
+#[inline]
+pub(crate) fn as_uninit_bytes_mut<D: Sized + Copy>(
+    data: &mut [MaybeUninit<D>],
+) -> &mut [MaybeUninit<u8>] {
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            data.as_mut_ptr() as *mut MaybeUninit<u8>,
+            std::mem::size_of_val(data),
+        )
+    }
+}
+
+/// Whether `len` elements starting at `offset` fit in `total`, without overflowing.
+#[inline]
+pub(crate) fn range_fits(offset: usize, len: usize, total: usize) -> bool {
+    offset.checked_add(len).is_some_and(|end| end <= total)
+}
+
+/// Whether two ranges of `size` bytes starting at `a` and `b` overlap, without overflowing.
+#[inline]
+pub(crate) fn ranges_overlap(a: u64, b: u64, size: u64) -> bool {
+    let before = |start: u64, other: u64| start.checked_add(size).is_some_and(|end| end <= other);
+    size > 0 && !before(a, b) && !before(b, a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_fits_bounds() {
+        assert!(range_fits(0, 4, 4));
+        assert!(range_fits(4, 0, 4));
+        assert!(range_fits(1, 2, 4));
+        assert!(!range_fits(1, 4, 4));
+        assert!(!range_fits(5, 0, 4));
+    }
+
+    #[test]
+    fn range_fits_overflow() {
+        assert!(!range_fits(usize::MAX, 1, 4));
+        assert!(!range_fits(1, usize::MAX, 4));
+        assert!(!range_fits(usize::MAX, usize::MAX, usize::MAX));
+        assert!(range_fits(usize::MAX, 0, usize::MAX));
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        assert!(ranges_overlap(0, 0, 4));
+        assert!(ranges_overlap(0, 3, 4));
+        assert!(ranges_overlap(3, 0, 4));
+        assert!(!ranges_overlap(0, 4, 4));
+        assert!(!ranges_overlap(4, 0, 4));
+        assert!(!ranges_overlap(0, 0, 0));
+    }
+
+    #[test]
+    fn overlapping_ranges_overflow() {
+        assert!(ranges_overlap(u64::MAX, u64::MAX - 1, 4));
+        assert!(ranges_overlap(u64::MAX - 1, u64::MAX, 4));
+        assert!(!ranges_overlap(0, u64::MAX - 3, 4));
+        assert!(ranges_overlap(1, u64::MAX - 1, u64::MAX));
+    }
+}